@@ -0,0 +1,280 @@
+use crate::config::Config;
+use crate::moderation::{self, LoginKey, LoginThrottle};
+use crate::{escape_html, identity, passwords, render_template, sanitize_board_name};
+use actix_files::NamedFile;
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie, SameSite};
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound, InternalError};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, Result};
+use futures_util::future::LocalBoxFuture;
+use log::{info, warn};
+use mysql_async::prelude::*;
+use mysql_async::{Conn, Pool};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Files of private boards live here, one directory per board, outside of /static
+pub const PRIVATE_MEDIA_DIR: &str = "./private";
+const PUBLIC_MEDIA_DIR: &str = "./static";
+const SESSION_DAYS: i64 = 30;
+
+// Who can find and read a board, stored in the `visibility` column of the `boards` table
+#[derive(Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Public,
+    // Left off the front page and the overboard, but open to anyone with the link
+    Unlisted,
+    // Only for visitors who logged in with the board password or an invite
+    Private,
+}
+
+impl Visibility {
+    pub fn from_setting(setting: &str) -> Visibility {
+        match setting {
+            "unlisted" => Visibility::Unlisted,
+            "private" => Visibility::Private,
+            _ => Visibility::Public,
+        }
+    }
+}
+
+// Where a board's uploads are written
+pub fn media_dir(board_name: &str, visibility: Visibility) -> String {
+    if visibility == Visibility::Private {
+        format!("{}/{}", PRIVATE_MEDIA_DIR, board_name)
+    } else {
+        PUBLIC_MEDIA_DIR.to_string()
+    }
+}
+
+fn session_cookie_name(board_name: &str) -> String {
+    format!("board_{}", board_name)
+}
+
+// Proof that the visitor may read the board named in the route.
+// Handlers taking this as an argument send visitors of a private board without a session to its login page.
+// Staff whose role covers the board get in with their moderation session.
+pub struct BoardAccess;
+
+impl FromRequest for BoardAccess {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let pool = req.app_data::<web::Data<Pool>>().cloned();
+        let board_name = sanitize_board_name(req.match_info().get("board_name").unwrap_or(""));
+        let board_token = req.cookie(&session_cookie_name(&board_name)).map(|cookie| cookie.value().to_string());
+        let staff_token = req.cookie(moderation::SESSION_COOKIE).map(|cookie| cookie.value().to_string());
+
+        Box::pin(async move {
+            let Some(pool) = pool else {
+                return Err(ErrorInternalServerError("database pool missing"));
+            };
+            let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+            let query = "SELECT visibility FROM boards WHERE name = ?";
+            info!("Executing query: {}", query);
+            let visibility: Option<String> = conn.exec_first(query, (&board_name,)).await.map_err(ErrorInternalServerError)?;
+            if visibility.as_deref().map(Visibility::from_setting) != Some(Visibility::Private) {
+                return Ok(BoardAccess);
+            }
+
+            if let Some(token) = board_token {
+                let session_query = "SELECT COUNT(*) FROM board_sessions WHERE token = ? AND board = ? AND expires_at > CURRENT_TIMESTAMP";
+                info!("Executing query: {}", session_query);
+                let sessions: Option<i64> = conn.exec_first(session_query, (token, &board_name)).await.map_err(ErrorInternalServerError)?;
+                if sessions.unwrap_or(0) > 0 {
+                    return Ok(BoardAccess);
+                }
+            }
+            if let Some(token) = staff_token {
+                let staff = moderation::session_staff(&mut conn, &token).await.map_err(ErrorInternalServerError)?;
+                if staff.is_some_and(|staff| staff.covers(&board_name)) {
+                    return Ok(BoardAccess);
+                }
+            }
+
+            Err(InternalError::from_response(
+                "board login required",
+                HttpResponse::SeeOther().append_header(("Location", format!("/{}/login", board_name))).finish(),
+            )
+            .into())
+        })
+    }
+}
+
+fn login_page(board_name: &str, invite: &str, error: &str) -> HttpResponse {
+    let mut context = HashMap::new();
+    context.insert("BOARD_NAME", board_name.to_string());
+    context.insert("INVITE", escape_html(invite));
+    context.insert("ERROR", escape_html(error));
+    let body = render_template("templates/board_login.html", &context);
+    HttpResponse::Ok().content_type("text/html").body(body)
+}
+
+// Invite links point here with `?invite=`, the token is only redeemed once the form is sent
+pub async fn show_login(path: web::Path<String>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    Ok(login_page(&board_name, query.get("invite").map(String::as_str).unwrap_or(""), ""))
+}
+
+// Use up one invite, returning whether it was valid for the board. Limited invites are checked and counted down in
+// one statement, so two logins at once can't both take the last use.
+async fn redeem_invite(conn: &mut Conn, board_name: &str, token: &str) -> Result<bool, mysql_async::Error> {
+    let use_query = "UPDATE board_invites SET uses_left = uses_left - 1 WHERE token = ? AND board = ? \
+                     AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP) AND uses_left > 0";
+    info!("Executing query: {}", use_query);
+    conn.exec_drop(use_query, (token, board_name)).await?;
+    if conn.affected_rows() > 0 {
+        return Ok(true);
+    }
+
+    let query = "SELECT COUNT(*) FROM board_invites WHERE token = ? AND board = ? \
+                 AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP) AND uses_left IS NULL";
+    info!("Executing query: {}", query);
+    let unlimited: Option<i64> = conn.exec_first(query, (token, board_name)).await?;
+    Ok(unlimited.unwrap_or(0) > 0)
+}
+
+pub async fn login(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+    throttle: web::Data<LoginThrottle>,
+    path: web::Path<String>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let password = form.get("password").map(String::as_str).unwrap_or("");
+    let invite = form.get("invite").map(|invite| invite.trim()).unwrap_or("");
+
+    let mut keys = vec![LoginKey::Board(board_name.clone())];
+    keys.extend(identity::client_ip(&req, &config).map(LoginKey::Address));
+    if throttle.is_blocked(&keys) {
+        return Ok(HttpResponse::TooManyRequests().body("Too many failed logins, try again later."));
+    }
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = "SELECT access_password FROM boards WHERE name = ? AND visibility = 'private'";
+    info!("Executing query: {}", query);
+    let board: Option<Option<String>> = conn.exec_first(query, (&board_name,)).await.map_err(ErrorInternalServerError)?;
+    let Some(password_hash) = board else {
+        return Ok(HttpResponse::SeeOther().append_header(("Location", format!("/{}", board_name))).finish());
+    };
+
+    let granted = if !password.is_empty() {
+        password_hash.is_some_and(|hash| passwords::verify_password(password, &hash))
+    } else if !invite.is_empty() {
+        redeem_invite(&mut conn, &board_name, invite).await.map_err(ErrorInternalServerError)?
+    } else {
+        false
+    };
+    if !granted {
+        throttle.record_failure(&keys);
+        return Ok(login_page(&board_name, invite, "Wrong password or invalid invite."));
+    }
+    throttle.clear(&keys);
+
+    let token = moderation::random_token(64);
+    let session_query = format!(
+        "INSERT INTO board_sessions (token, board, expires_at) VALUES (?, ?, CURRENT_TIMESTAMP + INTERVAL {} DAY)",
+        SESSION_DAYS
+    );
+    info!("Executing query: {}", session_query);
+    conn.exec_drop(&session_query, (&token, &board_name)).await.map_err(ErrorInternalServerError)?;
+
+    let cookie = Cookie::build(session_cookie_name(&board_name), token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::days(SESSION_DAYS))
+        .finish();
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/{}", board_name))).cookie(cookie).finish())
+}
+
+// Files of private boards, only for visitors who may read the board
+pub async fn media(_access: BoardAccess, path: web::Path<(String, String)>) -> Result<NamedFile> {
+    let (board_name, file_name) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let file_name = sanitize_filename::sanitize(&file_name);
+    let file_path = Path::new(PRIVATE_MEDIA_DIR).join(board_name).join(file_name);
+    NamedFile::open_async(file_path).await.map_err(|_| ErrorNotFound("File not found."))
+}
+
+// Set or, with `None`, remove the board password. Everyone logged in to the board is logged out, so the old
+// password stops working for sessions it already opened.
+pub async fn set_password(conn: &mut Conn, board_name: &str, password: Option<&str>) -> Result<(), String> {
+    let password_hash = match password {
+        Some(password) => Some(passwords::hash_password(password).map_err(|err| err.to_string())?),
+        None => None,
+    };
+    let query = "UPDATE boards SET access_password = ? WHERE name = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (password_hash, board_name)).await.map_err(|err| err.to_string())?;
+
+    let sessions_query = "DELETE FROM board_sessions WHERE board = ?";
+    info!("Executing query: {}", sessions_query);
+    conn.exec_drop(sessions_query, (board_name,)).await.map_err(|err| err.to_string())
+}
+
+// A new invite token for the board, optionally limited in uses and lifetime
+pub async fn create_invite(conn: &mut Conn, board_name: &str, uses: Option<u32>, days: Option<u32>) -> Result<String, mysql_async::Error> {
+    let token = moderation::random_token(32);
+    let query = "INSERT INTO board_invites (token, board, uses_left, expires_at) \
+                 VALUES (?, ?, ?, IF(? IS NULL, NULL, CURRENT_TIMESTAMP + INTERVAL ? DAY))";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (&token, board_name, uses, days, days)).await?;
+    Ok(token)
+}
+
+// Move a board's files to where its visibility wants them, returning how many were moved
+pub async fn relocate_media(conn: &mut Conn, board_name: &str, visibility: Visibility) -> Result<usize, mysql_async::Error> {
+    let target_dir = media_dir(board_name, visibility);
+    if let Err(err) = tokio::fs::create_dir_all(&target_dir).await {
+        warn!("Could not create {}: {}", target_dir, err);
+        return Ok(0);
+    }
+
+    let files_query = format!("SELECT id, file_path FROM {}_files WHERE file_path IS NOT NULL", board_name);
+    info!("Executing query: {}", files_query);
+    let files: Vec<(i32, String)> = conn.query(&files_query).await?;
+
+    let update_query = format!("UPDATE {}_files SET file_path = ?, last_reply_at = last_reply_at WHERE id = ?", board_name);
+    let mut moved = 0;
+    for (id, file_path) in files {
+        let Some(file_name) = Path::new(&file_path).file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let new_path = format!("{}/{}", target_dir, file_name);
+        if new_path == file_path {
+            continue;
+        }
+        if let Err(err) = tokio::fs::rename(&file_path, &new_path).await {
+            warn!("Could not move {} to {}: {}", file_path, new_path, err);
+            continue;
+        }
+        info!("Executing query: {}", update_query);
+        conn.exec_drop(&update_query, (&new_path, id)).await?;
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+// Follow a board rename with its private media directory and the paths stored in its posts
+pub async fn rename_media(conn: &mut Conn, old_name: &str, new_name: &str) -> Result<(), mysql_async::Error> {
+    let old_dir = format!("{}/{}", PRIVATE_MEDIA_DIR, old_name);
+    let new_dir = format!("{}/{}", PRIVATE_MEDIA_DIR, new_name);
+    if !Path::new(&old_dir).exists() {
+        return Ok(());
+    }
+    if let Err(err) = tokio::fs::rename(&old_dir, &new_dir).await {
+        warn!("Could not move {} to {}: {}", old_dir, new_dir, err);
+        return Ok(());
+    }
+
+    let query = format!(
+        "UPDATE {}_files SET file_path = CONCAT(?, SUBSTRING(file_path, ?)), last_reply_at = last_reply_at WHERE file_path LIKE CONCAT(?, '/%')",
+        new_name
+    );
+    info!("Executing query: {}", query);
+    conn.exec_drop(&query, (&new_dir, old_dir.len() + 1, &old_dir)).await
+}
@@ -0,0 +1,397 @@
+use crate::access::BoardAccess;
+use crate::boards::{self, BoardSettings};
+use crate::media_info;
+use crate::{escape_html, media_url, sanitize_board_name};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::{HttpDate, IfModifiedSince, LastModified};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Result};
+use chrono::DateTime;
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, FromRowError, Pool, Row};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Replies shown under each thread on index pages and in the catalog
+const LAST_REPLIES: usize = 5;
+// Thumbnails are the full files scaled down by the browser, these are the boxes clients should fit them into
+const OP_THUMBNAIL_SIZE: u32 = 250;
+const REPLY_THUMBNAIL_SIZE: u32 = 125;
+
+// A post as the API sees it, with its thread and bump time
+struct ApiPost {
+    id: i32,
+    parent_id: i32,
+    poster_id: Option<String>,
+    name: String,
+    tripcode: Option<String>,
+    title: String,
+    message: String,
+    file_path: Option<String>,
+    created_at: i64,
+    bumped_at: i64,
+}
+
+impl FromRow for ApiPost {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        let (id, parent_id, poster_id, name, tripcode, title, message, file_path, created_at, bumped_at) = mysql_async::from_row_opt(row)?;
+        Ok(ApiPost { id, parent_id, poster_id, name, tripcode, title, message, file_path, created_at, bumped_at })
+    }
+}
+
+const API_POST_COLUMNS: &str = "id, parent_id, poster_id, name, tripcode, title, message, file_path, \
+    UNIX_TIMESTAMP(created_at), UNIX_TIMESTAMP(last_reply_at)";
+
+// Reply and file counts of every thread on a board
+struct ThreadCounts {
+    replies: i64,
+    images: i64,
+}
+
+async fn load_threads(conn: &mut Conn, board_name: &str) -> Result<Vec<ApiPost>, mysql_async::Error> {
+    let query = format!(
+        "SELECT {} FROM {}_files WHERE parent_id = 0 AND pending = FALSE ORDER BY last_reply_at DESC",
+        API_POST_COLUMNS, board_name
+    );
+    info!("Executing query: {}", query);
+    conn.query(&query).await
+}
+
+async fn load_counts(conn: &mut Conn, board_name: &str) -> Result<HashMap<i32, ThreadCounts>, mysql_async::Error> {
+    let query = format!(
+        "SELECT parent_id, COUNT(*), COUNT(file_path) FROM {}_files WHERE parent_id <> 0 AND pending = FALSE GROUP BY parent_id",
+        board_name
+    );
+    info!("Executing query: {}", query);
+    let rows: Vec<(i32, i64, i64)> = conn.query(&query).await?;
+    Ok(rows.into_iter().map(|(thread, replies, images)| (thread, ThreadCounts { replies, images })).collect())
+}
+
+// The newest replies of each of `threads`, oldest first
+async fn load_last_replies(conn: &mut Conn, board_name: &str, threads: &[i32]) -> Result<HashMap<i32, Vec<ApiPost>>, mysql_async::Error> {
+    if threads.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let ids: Vec<String> = threads.iter().map(i32::to_string).collect();
+    let query = format!(
+        "SELECT {columns} FROM (SELECT *, ROW_NUMBER() OVER (PARTITION BY parent_id ORDER BY id DESC) AS newest \
+         FROM {board}_files WHERE parent_id IN ({ids}) AND pending = FALSE) replies WHERE newest <= {limit} ORDER BY id",
+        columns = API_POST_COLUMNS,
+        board = board_name,
+        ids = ids.join(", "),
+        limit = LAST_REPLIES
+    );
+    info!("Executing query: {}", query);
+    let replies: Vec<ApiPost> = conn.query(&query).await?;
+    let mut by_thread: HashMap<i32, Vec<ApiPost>> = HashMap::new();
+    for reply in replies {
+        by_thread.entry(reply.parent_id).or_default().push(reply);
+    }
+    Ok(by_thread)
+}
+
+// Fit the file into the thumbnail box, keeping its aspect ratio
+fn thumbnail_size((width, height): (u32, u32), limit: u32) -> (u32, u32) {
+    if width <= limit && height <= limit {
+        return (width, height);
+    }
+    let scale = limit as f64 / width.max(height) as f64;
+    (((width as f64 * scale).round() as u32).max(1), ((height as f64 * scale).round() as u32).max(1))
+}
+
+// A post in 4chan's layout: escaped HTML in `com`, `resto` 0 for thread openers
+async fn post_json(post: &ApiPost, settings: &BoardSettings) -> Map<String, Value> {
+    let mut json = Map::new();
+    json.insert("no".into(), json!(post.id));
+    json.insert("resto".into(), json!(post.parent_id));
+    json.insert("time".into(), json!(post.created_at));
+    if let Some(time) = DateTime::from_timestamp(post.created_at, 0) {
+        json.insert("now".into(), json!(time.format("%m/%d/%y(%a)%H:%M:%S").to_string()));
+    }
+    json.insert("name".into(), json!(escape_html(&post.name)));
+    if let Some(tripcode) = &post.tripcode {
+        json.insert("trip".into(), json!(escape_html(tripcode)));
+    }
+    if let Some(poster_id) = post.poster_id.as_ref().filter(|_| settings.poster_ids) {
+        json.insert("id".into(), json!(poster_id));
+    }
+    if !post.title.is_empty() {
+        json.insert("sub".into(), json!(escape_html(&post.title)));
+    }
+    json.insert("com".into(), json!(escape_html(&post.message).replace("\r\n", "\n").replace('\n', "<br>")));
+
+    if let Some(file_path) = &post.file_path {
+        let stored_name = file_path.rsplit('/').next().unwrap_or(file_path);
+        let ext = media_info::extension(stored_name);
+        let stem = stored_name.strip_suffix(&format!(".{}", ext)).unwrap_or(stored_name);
+        // Stored names are a random prefix and a dash in front of the uploaded name
+        let filename = stem.split_once('-').map_or(stem, |(_, original)| original);
+        json.insert("filename".into(), json!(filename));
+        json.insert("ext".into(), json!(format!(".{}", ext)));
+        json.insert("tim".into(), json!(stem));
+        json.insert("mime".into(), json!(media_info::mime_type(file_path)));
+        json.insert("file_url".into(), json!(media_url(file_path)));
+        if let Some(info) = media_info::inspect(file_path).await {
+            json.insert("fsize".into(), json!(info.size));
+            if let Some((width, height)) = info.dimensions {
+                let limit = if post.parent_id == 0 { OP_THUMBNAIL_SIZE } else { REPLY_THUMBNAIL_SIZE };
+                let (tn_w, tn_h) = thumbnail_size((width, height), limit);
+                json.insert("w".into(), json!(width));
+                json.insert("h".into(), json!(height));
+                json.insert("tn_w".into(), json!(tn_w));
+                json.insert("tn_h".into(), json!(tn_h));
+            }
+        } else {
+            json.insert("filedeleted".into(), json!(1));
+        }
+    }
+    json
+}
+
+// A thread opener with its counts and, when given, its newest replies
+async fn thread_json(op: &ApiPost, counts: Option<&ThreadCounts>, last_replies: &[ApiPost], settings: &BoardSettings) -> Map<String, Value> {
+    let replies = counts.map_or(0, |counts| counts.replies);
+    let images = counts.map_or(0, |counts| counts.images);
+    let shown_images = last_replies.iter().filter(|reply| reply.file_path.is_some()).count() as i64;
+    let mut json = post_json(op, settings).await;
+    json.insert("replies".into(), json!(replies));
+    json.insert("images".into(), json!(images));
+    json.insert("omitted_posts".into(), json!(replies - last_replies.len() as i64));
+    json.insert("omitted_images".into(), json!(images - shown_images));
+    json.insert("last_modified".into(), json!(op.bumped_at));
+    json
+}
+
+fn page_count(threads: usize, settings: &BoardSettings) -> usize {
+    threads.div_ceil(settings.threads_per_page).max(1)
+}
+
+fn system_time(timestamp: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+}
+
+// JSON with a Last-Modified header, or 304 when the client's copy is still current
+fn respond(req: &HttpRequest, last_modified: Option<i64>, body: Value) -> HttpResponse {
+    if let Some(response) = not_modified(req, last_modified) {
+        return response;
+    }
+    let mut response = HttpResponse::Ok();
+    if let Some(last_modified) = last_modified {
+        response.insert_header(LastModified(HttpDate::from(system_time(last_modified))));
+    }
+    response.insert_header(("Access-Control-Allow-Origin", "*")).json(body)
+}
+
+// Answer 304 before doing the expensive part of a response
+fn not_modified(req: &HttpRequest, last_modified: Option<i64>) -> Option<HttpResponse> {
+    let modified = system_time(last_modified?);
+    let IfModifiedSince(since) = req.get_header::<IfModifiedSince>()?;
+    (modified <= SystemTime::from(since)).then(|| HttpResponse::NotModified().finish())
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(json!({ "error": "Not found" }))
+}
+
+async fn board_exists(conn: &mut Conn, board_name: &str) -> Result<bool, mysql_async::Error> {
+    let query = "SELECT COUNT(*) FROM boards WHERE name = ?";
+    info!("Executing query: {}", query);
+    let count: Option<i64> = conn.exec_first(query, (board_name,)).await?;
+    Ok(count.unwrap_or(0) > 0)
+}
+
+// Every public board with the limits clients need to know before posting
+pub async fn boards_list(req: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse> {
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = "SELECT name FROM boards WHERE visibility = 'public' ORDER BY name";
+    info!("Executing query: {}", query);
+    let names: Vec<String> = conn.query(query).await.map_err(ErrorInternalServerError)?;
+
+    let mut boards_json = Vec::new();
+    let mut last_modified: Option<i64> = None;
+    for name in names {
+        let board_name = sanitize_board_name(&name);
+        let settings = boards::load_settings(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+        let count_query = format!(
+            "SELECT COUNT(*), UNIX_TIMESTAMP(MAX(last_reply_at)) FROM {}_files WHERE parent_id = 0 AND pending = FALSE",
+            board_name
+        );
+        info!("Executing query: {}", count_query);
+        let counts: Option<(i64, Option<i64>)> = conn.query_first(&count_query).await.map_err(ErrorInternalServerError)?;
+        let (threads, bumped_at) = counts.unwrap_or((0, None));
+        last_modified = last_modified.max(bumped_at);
+        boards_json.push(json!({
+            "board": board_name,
+            "title": settings.display_title(&board_name),
+            "ws_board": if settings.nsfw { 0 } else { 1 },
+            "per_page": settings.threads_per_page,
+            "pages": page_count(threads as usize, &settings),
+            "max_comment_chars": settings.max_message_length,
+            "max_subject_chars": settings.max_title_length,
+            "meta_description": settings.description,
+            "text_only": settings.text_only as i32,
+            "forced_anon": settings.forced_anon as i32,
+            "user_ids": settings.poster_ids as i32,
+            "file_types": settings.allowed_file_types,
+        }));
+    }
+    // The newest bump on any board; an edit to a board's settings alone doesn't move it
+    Ok(respond(&req, last_modified, json!({ "boards": boards_json })))
+}
+
+// Thread numbers and bump times per page, for clients polling a board for changes
+pub async fn threads_list(req: HttpRequest, pool: web::Data<Pool>, _access: BoardAccess, path: web::Path<String>) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    if !board_exists(&mut conn, &board_name).await.map_err(ErrorInternalServerError)? {
+        return Ok(not_found());
+    }
+    let settings = boards::load_settings(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let threads = load_threads(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let counts = load_counts(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+
+    let pages: Vec<Value> = threads
+        .chunks(settings.threads_per_page)
+        .enumerate()
+        .map(|(index, threads)| {
+            let threads: Vec<Value> = threads
+                .iter()
+                .map(|op| json!({
+                    "no": op.id,
+                    "last_modified": op.bumped_at,
+                    "replies": counts.get(&op.id).map_or(0, |counts| counts.replies),
+                }))
+                .collect();
+            json!({ "page": index + 1, "threads": threads })
+        })
+        .collect();
+    let last_modified = threads.iter().map(|op| op.bumped_at).max();
+    Ok(respond(&req, last_modified, Value::from(pages)))
+}
+
+// Every thread opener with its newest replies, split into pages like the board index
+pub async fn catalog(req: HttpRequest, pool: web::Data<Pool>, _access: BoardAccess, path: web::Path<String>) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    if !board_exists(&mut conn, &board_name).await.map_err(ErrorInternalServerError)? {
+        return Ok(not_found());
+    }
+    let settings = boards::load_settings(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let threads = load_threads(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let last_modified = threads.iter().map(|op| op.bumped_at).max();
+    if let Some(response) = not_modified(&req, last_modified) {
+        return Ok(response);
+    }
+    let counts = load_counts(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let ids: Vec<i32> = threads.iter().map(|op| op.id).collect();
+    let last_replies = load_last_replies(&mut conn, &board_name, &ids).await.map_err(ErrorInternalServerError)?;
+
+    let mut pages = Vec::new();
+    for (index, page_threads) in threads.chunks(settings.threads_per_page).enumerate() {
+        let mut threads_json = Vec::new();
+        for op in page_threads {
+            let replies = last_replies.get(&op.id).map(Vec::as_slice).unwrap_or(&[]);
+            let mut thread = thread_json(op, counts.get(&op.id), replies, &settings).await;
+            let mut replies_json = Vec::new();
+            for reply in replies {
+                replies_json.push(Value::from(post_json(reply, &settings).await));
+            }
+            if !replies_json.is_empty() {
+                thread.insert("last_replies".into(), Value::from(replies_json));
+            }
+            threads_json.push(Value::from(thread));
+        }
+        pages.push(json!({ "page": index + 1, "threads": threads_json }));
+    }
+    Ok(respond(&req, last_modified, Value::from(pages)))
+}
+
+// One page of the board index: each thread's opener followed by its newest replies
+pub async fn index_page(req: HttpRequest, pool: web::Data<Pool>, _access: BoardAccess, path: web::Path<(String, usize)>) -> Result<HttpResponse> {
+    let (board_name, page) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    if !board_exists(&mut conn, &board_name).await.map_err(ErrorInternalServerError)? {
+        return Ok(not_found());
+    }
+    let settings = boards::load_settings(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let threads = load_threads(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let Some(page_threads) = threads.chunks(settings.threads_per_page).nth(page.saturating_sub(1)).filter(|_| page > 0) else {
+        return Ok(not_found());
+    };
+    let last_modified = page_threads.iter().map(|op| op.bumped_at).max();
+    if let Some(response) = not_modified(&req, last_modified) {
+        return Ok(response);
+    }
+    let counts = load_counts(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let ids: Vec<i32> = page_threads.iter().map(|op| op.id).collect();
+    let last_replies = load_last_replies(&mut conn, &board_name, &ids).await.map_err(ErrorInternalServerError)?;
+
+    let mut threads_json = Vec::new();
+    for op in page_threads {
+        let replies = last_replies.get(&op.id).map(Vec::as_slice).unwrap_or(&[]);
+        let mut posts = vec![Value::from(thread_json(op, counts.get(&op.id), replies, &settings).await)];
+        for reply in replies {
+            posts.push(Value::from(post_json(reply, &settings).await));
+        }
+        threads_json.push(json!({ "posts": posts }));
+    }
+    Ok(respond(&req, last_modified, json!({ "threads": threads_json })))
+}
+
+// A whole thread, opener first
+pub async fn thread(req: HttpRequest, pool: web::Data<Pool>, _access: BoardAccess, path: web::Path<(String, i32)>) -> Result<HttpResponse> {
+    let (board_name, id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    if !board_exists(&mut conn, &board_name).await.map_err(ErrorInternalServerError)? {
+        return Ok(not_found());
+    }
+    let settings = boards::load_settings(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    let query = format!(
+        "SELECT {} FROM {}_files WHERE (id = ? AND parent_id = 0 OR parent_id = ?) AND pending = FALSE ORDER BY id",
+        API_POST_COLUMNS, board_name
+    );
+    info!("Executing query: {}", query);
+    let posts: Vec<ApiPost> = conn.exec(&query, (id, id)).await.map_err(ErrorInternalServerError)?;
+    let Some(op) = posts.first().filter(|op| op.id == id) else {
+        return Ok(not_found());
+    };
+
+    let last_modified = posts.iter().map(|post| post.created_at).max().map(|newest| newest.max(op.bumped_at));
+    if let Some(response) = not_modified(&req, last_modified) {
+        return Ok(response);
+    }
+    let counts = ThreadCounts {
+        replies: posts.len() as i64 - 1,
+        images: posts[1..].iter().filter(|reply| reply.file_path.is_some()).count() as i64,
+    };
+    let mut posts_json = vec![Value::from(thread_json(op, Some(&counts), &posts[1..], &settings).await)];
+    for reply in &posts[1..] {
+        posts_json.push(Value::from(post_json(reply, &settings).await));
+    }
+    Ok(respond(&req, last_modified, json!({ "posts": posts_json })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_files_keep_their_size() {
+        assert_eq!(thumbnail_size((100, 80), OP_THUMBNAIL_SIZE), (100, 80));
+        assert_eq!(thumbnail_size((250, 250), OP_THUMBNAIL_SIZE), (250, 250));
+    }
+
+    #[test]
+    fn large_files_fit_the_box_keeping_aspect_ratio() {
+        assert_eq!(thumbnail_size((1000, 500), OP_THUMBNAIL_SIZE), (250, 125));
+        assert_eq!(thumbnail_size((300, 1200), REPLY_THUMBNAIL_SIZE), (31, 125));
+    }
+
+    #[test]
+    fn thin_files_keep_at_least_one_pixel() {
+        assert_eq!(thumbnail_size((10000, 1), REPLY_THUMBNAIL_SIZE), (125, 1));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::audit::{self, Action};
+use crate::moderation::{self, Staff};
+use crate::{boards, deletion, r9k, render_template, sanitize_board_name, visitor_timezone};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::Pool;
+use std::collections::HashMap;
+
+const QUEUE_SIZE: usize = 100;
+
+// Which new posts a board holds for approval, stored in the `approval` column of the `boards` table
+#[derive(Clone, Copy, PartialEq)]
+pub enum ApprovalMode {
+    Off,
+    Threads,
+    Files,
+    All,
+}
+
+impl ApprovalMode {
+    pub fn from_setting(setting: &str) -> ApprovalMode {
+        match setting {
+            "threads" => ApprovalMode::Threads,
+            "files" => ApprovalMode::Files,
+            "all" => ApprovalMode::All,
+            _ => ApprovalMode::Off,
+        }
+    }
+
+    pub fn holds(self, is_reply: bool, has_file: bool) -> bool {
+        match self {
+            ApprovalMode::Off => false,
+            ApprovalMode::Threads => !is_reply,
+            ApprovalMode::Files => has_file,
+            ApprovalMode::All => true,
+        }
+    }
+}
+
+// Posts held back from the public, oldest first so nothing waits forever
+pub async fn pending_queue(
+    req: HttpRequest,
+    staff: Staff,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let boards_query = "SELECT name FROM boards ORDER BY name";
+    info!("Executing query: {}", boards_query);
+    let boards: Vec<String> = conn.query(boards_query).await.map_err(ErrorInternalServerError)?;
+    let posts = moderation::posts_across_boards(&mut conn, &boards, "pending = TRUE", moderation::Order::OldestFirst, QUEUE_SIZE)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let timezone = visitor_timezone(&req);
+    let mut posts_html = String::new();
+    for post in &posts {
+        posts_html.push_str("<div class=\"report-entry\">");
+        posts_html.push_str(&moderation::render_mod_post(post, &staff, timezone));
+        posts_html.push_str(&format!(
+            r#"<form class="delete-form" action="/mod/pending/{board}/{id}/approve" method="post"><button type="submit">Approve</button></form><form class="delete-form" action="/mod/pending/{board}/{id}/reject" method="post"><input type="text" name="reason" placeholder="Reason (optional)"><button type="submit">Reject</button></form>"#,
+            board = post.board,
+            id = post.id
+        ));
+        posts_html.push_str("</div>");
+    }
+    if posts_html.is_empty() {
+        posts_html.push_str("<p>No posts are waiting for approval.</p>");
+    }
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", moderation::nav(&staff));
+    context.insert("POSTS", posts_html);
+    let body = render_template("templates/mod_pending.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+// Publish a held post; it shows up as if it had just been made and bumps its thread
+pub async fn approve(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse> {
+    let (board_name, id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let table_name = format!("{}_files", board_name);
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = format!("SELECT parent_id, message, file_path FROM {} WHERE id = ? AND pending = TRUE", table_name);
+    info!("Executing query: {}", query);
+    let post: Option<(i32, String, Option<String>)> = conn.exec_first(&query, (id,)).await.map_err(ErrorInternalServerError)?;
+    let Some((parent_id, message, file_path)) = post else {
+        return Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/pending")).finish());
+    };
+
+    let thread_id = if parent_id == 0 { id } else { parent_id };
+    let update_query = format!("UPDATE {} SET pending = FALSE WHERE id = ?", table_name);
+    info!("Executing query: {}", update_query);
+    conn.exec_drop(&update_query, (id,)).await.map_err(ErrorInternalServerError)?;
+    let bump_query = format!(
+        "UPDATE {} SET last_reply_at = CURRENT_TIMESTAMP WHERE id = ? OR parent_id = ?",
+        table_name
+    );
+    info!("Executing query: {}", bump_query);
+    conn.exec_drop(&bump_query, (thread_id, thread_id)).await.map_err(ErrorInternalServerError)?;
+
+    let settings = boards::load_settings(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    if settings.r9k {
+        let image_hash = match file_path.filter(|_| settings.r9k_images) {
+            Some(file_path) => r9k::file_hash(&file_path).await,
+            None => None,
+        };
+        r9k::remember(&mut conn, &board_name, r9k::message_hash(&message).as_deref(), image_hash.as_deref())
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+
+    audit::record(&mut conn, &staff, Action::ApprovePost, Some(&board_name), Some(id), None, None)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/pending")).finish())
+}
+
+pub async fn reject(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<(String, i32)>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let (board_name, id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    deletion::delete_post(&mut conn, &board_name, id).await.map_err(ErrorInternalServerError)?;
+    let reason = form.get("reason").map(String::as_str);
+    audit::record(&mut conn, &staff, Action::RejectPost, Some(&board_name), Some(id), reason, None)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/pending")).finish())
+}
@@ -0,0 +1,330 @@
+use crate::access::BoardAccess;
+use crate::boards;
+use crate::moderation::{self, Staff};
+use crate::{escape_html, render_template, sanitize_board_name};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, FromRowError, Pool, Row, Value};
+use std::collections::HashMap;
+
+const ENTRIES_PER_PAGE: usize = 50;
+
+// Every kind of staff action that ends up in the log
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    DeletePost,
+    DeleteFile,
+    Ban,
+    LiftBan,
+    DismissReports,
+    AddStaff,
+    RemoveStaff,
+    SetRole,
+    CreateBoard,
+    EditBoard,
+    AddVolunteer,
+    RemoveVolunteer,
+    SaveFilter,
+    RemoveFilter,
+    ApprovePost,
+    RejectPost,
+}
+
+impl Action {
+    const ALL: [Action; 16] = [
+        Action::DeletePost,
+        Action::DeleteFile,
+        Action::Ban,
+        Action::LiftBan,
+        Action::DismissReports,
+        Action::AddStaff,
+        Action::RemoveStaff,
+        Action::SetRole,
+        Action::CreateBoard,
+        Action::EditBoard,
+        Action::AddVolunteer,
+        Action::RemoveVolunteer,
+        Action::SaveFilter,
+        Action::RemoveFilter,
+        Action::ApprovePost,
+        Action::RejectPost,
+    ];
+
+    fn key(self) -> &'static str {
+        match self {
+            Action::DeletePost => "delete_post",
+            Action::DeleteFile => "delete_file",
+            Action::Ban => "ban",
+            Action::LiftBan => "lift_ban",
+            Action::DismissReports => "dismiss_reports",
+            Action::AddStaff => "add_staff",
+            Action::RemoveStaff => "remove_staff",
+            Action::SetRole => "set_role",
+            Action::CreateBoard => "create_board",
+            Action::EditBoard => "edit_board",
+            Action::AddVolunteer => "add_volunteer",
+            Action::RemoveVolunteer => "remove_volunteer",
+            Action::SaveFilter => "save_filter",
+            Action::RemoveFilter => "remove_filter",
+            Action::ApprovePost => "approve_post",
+            Action::RejectPost => "reject_post",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Action::DeletePost => "Deleted post",
+            Action::DeleteFile => "Deleted file",
+            Action::Ban => "Banned",
+            Action::LiftBan => "Lifted ban",
+            Action::DismissReports => "Dismissed reports",
+            Action::AddStaff => "Added staff account",
+            Action::RemoveStaff => "Removed staff account",
+            Action::SetRole => "Changed staff role",
+            Action::CreateBoard => "Created board",
+            Action::EditBoard => "Changed board settings",
+            Action::AddVolunteer => "Added volunteer",
+            Action::RemoveVolunteer => "Removed volunteer",
+            Action::SaveFilter => "Saved filter",
+            Action::RemoveFilter => "Removed filter",
+            Action::ApprovePost => "Approved post",
+            Action::RejectPost => "Rejected post",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Action> {
+        Action::ALL.iter().copied().find(|action| action.key() == key)
+    }
+
+    // Actions that may appear in a board's public log
+    fn is_public(self) -> bool {
+        matches!(self, Action::DeletePost | Action::DeleteFile | Action::Ban | Action::RejectPost)
+    }
+}
+
+// Append an entry to the log. Nothing in the application updates or deletes entries afterwards.
+pub async fn record(
+    conn: &mut Conn,
+    staff: &Staff,
+    action: Action,
+    board: Option<&str>,
+    post_id: Option<i32>,
+    reason: Option<&str>,
+    details: Option<String>,
+) -> Result<(), mysql_async::Error> {
+    let reason = reason.map(str::trim).filter(|reason| !reason.is_empty());
+    let query = "INSERT INTO mod_log (staff_id, staff_name, action, board, post_id, reason, details) VALUES (?, ?, ?, ?, ?, ?, ?)";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (staff.id, &staff.username, action.key(), board, post_id, reason, details)).await
+}
+
+struct Entry {
+    staff_name: String,
+    action: String,
+    board: Option<String>,
+    post_id: Option<i32>,
+    reason: Option<String>,
+    details: Option<String>,
+    created_at: i64,
+}
+
+impl FromRow for Entry {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        let (staff_name, action, board, post_id, reason, details, created_at) = mysql_async::from_row_opt(row)?;
+        Ok(Entry { staff_name, action, board, post_id, reason, details, created_at })
+    }
+}
+
+const ENTRY_COLUMNS: &str = "staff_name, action, board, post_id, reason, details, UNIX_TIMESTAMP(created_at)";
+
+impl Entry {
+    fn action_label(&self) -> &str {
+        Action::from_key(&self.action).map(Action::label).unwrap_or(&self.action)
+    }
+
+    fn target(&self) -> String {
+        match (&self.board, self.post_id) {
+            (Some(board), Some(post_id)) => format!("/{}/ No.{}", board, post_id),
+            (Some(board), None) => format!("/{}/", board),
+            (None, _) => "-".to_string(),
+        }
+    }
+
+    fn time(&self) -> String {
+        DateTime::<Utc>::from_timestamp(self.created_at, 0)
+            .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+            .unwrap_or_default()
+    }
+}
+
+fn page_from_query(query: &HashMap<String, String>) -> usize {
+    query.get("page").and_then(|page| page.parse().ok()).filter(|page| *page > 0).unwrap_or(1)
+}
+
+// Percent-encode a value for a query string, leaving only unreserved characters as they are
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => char::from(byte).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn pagination(base: &str, page: usize, has_next: bool) -> String {
+    let mut html = String::new();
+    if page > 1 {
+        html.push_str(&format!(r#"<a href="{}page={}">Previous</a>"#, base, page - 1));
+    }
+    if has_next {
+        html.push_str(&format!(r#"<a href="{}page={}">Next</a>"#, base, page + 1));
+    }
+    html
+}
+
+// The full log for staff, filterable by staff member, action and board
+pub async fn show_log(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let staff_filter = query.get("staff").map(|name| name.trim().to_string()).filter(|name| !name.is_empty());
+    let action_filter = query.get("action").and_then(|key| Action::from_key(key));
+    let board_filter = query.get("board").map(|board| sanitize_board_name(board)).filter(|board| !board.is_empty());
+    let page = page_from_query(&query);
+
+    let mut conditions = vec!["1 = 1".to_string()];
+    let mut params: Vec<Value> = Vec::new();
+    if let Some(name) = &staff_filter {
+        conditions.push("staff_name = ?".to_string());
+        params.push(name.clone().into());
+    }
+    if let Some(action) = action_filter {
+        conditions.push("action = ?".to_string());
+        params.push(action.key().into());
+    }
+    if let Some(board) = &board_filter {
+        conditions.push("board = ?".to_string());
+        params.push(board.clone().into());
+    }
+
+    // One extra row tells whether there is a next page
+    let sql = format!(
+        "SELECT {} FROM mod_log WHERE {} ORDER BY id DESC LIMIT {} OFFSET {}",
+        ENTRY_COLUMNS,
+        conditions.join(" AND "),
+        ENTRIES_PER_PAGE + 1,
+        (page - 1) * ENTRIES_PER_PAGE
+    );
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    info!("Executing query: {}", sql);
+    let mut entries: Vec<Entry> = conn.exec(&sql, params).await.map_err(ErrorInternalServerError)?;
+    let has_next = entries.len() > ENTRIES_PER_PAGE;
+    entries.truncate(ENTRIES_PER_PAGE);
+
+    let mut entries_html = String::new();
+    for entry in &entries {
+        entries_html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            entry.time(),
+            escape_html(&entry.staff_name),
+            entry.action_label(),
+            entry.target(),
+            escape_html(entry.reason.as_deref().unwrap_or("")),
+            escape_html(entry.details.as_deref().unwrap_or(""))
+        ));
+    }
+
+    let action_options: String = Action::ALL
+        .iter()
+        .map(|action| {
+            let selected = if action_filter == Some(*action) { " selected" } else { "" };
+            format!("<option value=\"{}\"{}>{}</option>", action.key(), selected, action.label())
+        })
+        .collect();
+
+    let base = format!(
+        "/mod/log?staff={}&action={}&board={}&",
+        encode_query_value(staff_filter.as_deref().unwrap_or("")),
+        action_filter.map(Action::key).unwrap_or(""),
+        board_filter.as_deref().unwrap_or("")
+    );
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", moderation::nav(&staff));
+    context.insert("STAFF_FILTER", escape_html(staff_filter.as_deref().unwrap_or("")));
+    context.insert("BOARD_FILTER", board_filter.clone().unwrap_or_default());
+    context.insert("ACTION_OPTIONS", action_options);
+    context.insert("ENTRIES", entries_html);
+    context.insert("PAGINATION", pagination(&base, page, has_next));
+    let body = render_template("templates/mod_log.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+// The redacted log of one board: no staff names and no ban details, only on boards that opted in
+pub async fn public_log(
+    pool: web::Data<Pool>,
+    _access: BoardAccess,
+    path: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let page = page_from_query(&query);
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let settings = boards::load_settings(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    if !settings.public_log {
+        return Ok(HttpResponse::NotFound().body("This board has no public moderation log."));
+    }
+
+    let public_actions: Vec<String> = Action::ALL
+        .iter()
+        .filter(|action| action.is_public())
+        .map(|action| format!("'{}'", action.key()))
+        .collect();
+    let sql = format!(
+        "SELECT {} FROM mod_log WHERE board = ? AND action IN ({}) ORDER BY id DESC LIMIT {} OFFSET {}",
+        ENTRY_COLUMNS,
+        public_actions.join(", "),
+        ENTRIES_PER_PAGE + 1,
+        (page - 1) * ENTRIES_PER_PAGE
+    );
+    info!("Executing query: {}", sql);
+    let mut entries: Vec<Entry> = conn.exec(&sql, (&board_name,)).await.map_err(ErrorInternalServerError)?;
+    let has_next = entries.len() > ENTRIES_PER_PAGE;
+    entries.truncate(ENTRIES_PER_PAGE);
+
+    let mut entries_html = String::new();
+    for entry in &entries {
+        entries_html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            entry.time(),
+            entry.action_label(),
+            entry.target(),
+            escape_html(entry.reason.as_deref().unwrap_or(""))
+        ));
+    }
+
+    let mut context = HashMap::new();
+    context.insert("BOARD_NAME", format!("/{}", board_name));
+    context.insert("ENTRIES", entries_html);
+    context.insert("PAGINATION", pagination(&format!("/{}/log?", board_name), page, has_next));
+    let body = render_template("templates/board_log.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_values_are_percent_encoded() {
+        assert_eq!(encode_query_value("jan_mod-2.0~"), "jan_mod-2.0~");
+        assert_eq!(encode_query_value("a&page=9 \"<x>"), "a%26page%3D9%20%22%3Cx%3E");
+        assert_eq!(encode_query_value("é#"), "%C3%A9%23");
+    }
+}
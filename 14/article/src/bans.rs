@@ -0,0 +1,435 @@
+use crate::audit::{self, Action};
+use crate::config::Config;
+use crate::moderation::{self, Staff};
+use crate::{deletion, escape_html, reports, identity, render_template, sanitize_board_name};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, FromRowError, Pool, Row};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// Choices offered when placing a ban, in hours; 0 means permanent
+const DURATIONS: [(i64, &str); 6] = [
+    (1, "1 hour"),
+    (24, "1 day"),
+    (72, "3 days"),
+    (168, "1 week"),
+    (720, "30 days"),
+    (0, "Permanent"),
+];
+
+pub struct Ban {
+    id: i32,
+    ip_range: Option<String>,
+    ip_hash: Option<String>,
+    board: Option<String>,
+    reason: String,
+    created_at: i64,
+    expires_at: Option<i64>,
+}
+
+impl FromRow for Ban {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        let (id, ip_range, ip_hash, board, reason, created_at, expires_at) = mysql_async::from_row_opt(row)?;
+        Ok(Ban { id, ip_range, ip_hash, board, reason, created_at, expires_at })
+    }
+}
+
+const BAN_COLUMNS: &str = "id, ip_range, ip_hash, board, reason, UNIX_TIMESTAMP(created_at), UNIX_TIMESTAMP(expires_at)";
+
+impl Ban {
+    // Range bans match on the address itself, bans placed from hashed post IPs on the hash
+    fn matches(&self, ip: IpAddr, stored_ip: &str) -> bool {
+        let in_range = self.ip_range.as_deref()
+            .and_then(|range| range.parse::<IpNet>().ok())
+            .is_some_and(|range| range.contains(&ip));
+        in_range || self.ip_hash.as_deref() == Some(stored_ip)
+    }
+
+    fn target(&self) -> String {
+        match (&self.ip_range, &self.ip_hash) {
+            (Some(range), _) => range.clone(),
+            (None, Some(hash)) => format!("hash {}", &hash[..hash.len().min(12)]),
+            (None, None) => "-".to_string(),
+        }
+    }
+
+    fn scope(&self) -> String {
+        match &self.board {
+            Some(board) => format!("/{}/", board),
+            None => "all boards".to_string(),
+        }
+    }
+
+    fn expiry(&self) -> String {
+        match self.expires_at.and_then(|expires_at| DateTime::<Utc>::from_timestamp(expires_at, 0)) {
+            Some(expires_at) => expires_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            None => "never".to_string(),
+        }
+    }
+}
+
+// Active bans covering the given address, either global or for `board_name` when one is given
+pub async fn active_bans(conn: &mut Conn, ip: IpAddr, stored_ip: &str, board_name: Option<&str>) -> Result<Vec<Ban>, mysql_async::Error> {
+    let active = "(expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)";
+    let bans: Vec<Ban> = match board_name {
+        Some(board_name) => {
+            let query = format!("SELECT {} FROM bans WHERE (board IS NULL OR board = ?) AND {} ORDER BY id DESC", BAN_COLUMNS, active);
+            info!("Executing query: {}", query);
+            conn.exec(&query, (board_name,)).await?
+        },
+        None => {
+            let query = format!("SELECT {} FROM bans WHERE {} ORDER BY id DESC", BAN_COLUMNS, active);
+            info!("Executing query: {}", query);
+            conn.query(&query).await?
+        },
+    };
+    Ok(bans.into_iter().filter(|ban| ban.matches(ip, stored_ip)).collect())
+}
+
+// Whether the visitor may post on `board_name`
+pub async fn is_banned(pool: &Pool, config: &Config, ip: Option<IpAddr>, board_name: &str) -> Result<bool, mysql_async::Error> {
+    let Some(ip) = ip else {
+        return Ok(false);
+    };
+    let mut conn = pool.get_conn().await?;
+    let bans = active_bans(&mut conn, ip, &identity::stored_ip(ip, config), Some(board_name)).await?;
+    Ok(!bans.is_empty())
+}
+
+fn format_time(timestamp: i64) -> String {
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+pub async fn banned_page(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let mut bans_html = String::new();
+
+    if let Some(ip) = identity::client_ip(&req, &config) {
+        let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+        let bans = active_bans(&mut conn, ip, &identity::stored_ip(ip, &config), None).await.map_err(ErrorInternalServerError)?;
+        for ban in bans {
+            bans_html.push_str(&format!(
+                "<div class=\"post\"><div class=\"post-title\">You are banned from {}</div><div class=\"post-message\">Reason: {}</div><div>Placed on {}, expires {}.</div></div>",
+                ban.scope(),
+                escape_html(&ban.reason),
+                format_time(ban.created_at),
+                ban.expiry()
+            ));
+        }
+    }
+
+    if bans_html.is_empty() {
+        bans_html.push_str("<p>You are not banned.</p>");
+    }
+
+    let mut context = HashMap::new();
+    context.insert("BANS", bans_html);
+    let body = render_template("templates/banned.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub fn duration_options() -> String {
+    duration_options_with(DURATIONS[0].0)
+}
+
+pub fn duration_options_with(selected: i64) -> String {
+    DURATIONS
+        .iter()
+        .map(|(hours, label)| {
+            let selected = if *hours == selected { " selected" } else { "" };
+            format!("<option value=\"{}\"{}>{}</option>", hours, selected, label)
+        })
+        .collect()
+}
+
+fn expiry_from_form(form: &HashMap<String, String>) -> Option<i64> {
+    let hours: i64 = form.get("duration").and_then(|hours| hours.parse().ok()).unwrap_or(0);
+    if hours > 0 {
+        Some(Utc::now().timestamp() + hours * 3600)
+    } else {
+        None
+    }
+}
+
+fn board_from_form(form: &HashMap<String, String>) -> Option<String> {
+    form.get("board")
+        .map(|board| sanitize_board_name(board))
+        .filter(|board| !board.is_empty())
+}
+
+async fn insert_ban(
+    conn: &mut Conn,
+    staff_id: Option<i32>,
+    ip_range: Option<String>,
+    ip_hash: Option<String>,
+    board: Option<String>,
+    reason: &str,
+    expires_at: Option<i64>,
+) -> Result<(), mysql_async::Error> {
+    let query = "INSERT INTO bans (ip_range, ip_hash, board, reason, expires_at, staff_id) VALUES (?, ?, ?, ?, FROM_UNIXTIME(?), ?)";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (ip_range, ip_hash, board, reason, expires_at, staff_id)).await
+}
+
+// Posts made while IP hashing was enabled can only be banned by their exact hash
+fn ban_target(stored_ip: String) -> (Option<String>, Option<String>) {
+    match stored_ip.parse::<IpAddr>() {
+        Ok(ip) => (Some(IpNet::from(ip).to_string()), None),
+        Err(_) => (None, Some(stored_ip)),
+    }
+}
+
+// Ban the address stored with a post; used for bans placed automatically, without a staff member
+pub async fn ban_stored_ip(
+    conn: &mut Conn,
+    stored_ip: String,
+    board: Option<String>,
+    reason: &str,
+    expires_at: Option<i64>,
+) -> Result<(), mysql_async::Error> {
+    let (ip_range, ip_hash) = ban_target(stored_ip);
+    insert_ban(conn, None, ip_range, ip_hash, board, reason, expires_at).await
+}
+
+// What a ban covered, for the moderation log
+fn ban_details(target: &str, expires_at: Option<i64>) -> String {
+    let expiry = expires_at
+        .map(format_time)
+        .unwrap_or_else(|| "never".to_string());
+    format!("{}, expires {}", target, expiry)
+}
+
+async fn bans_page(staff: &Staff, pool: &Pool, message: &str) -> Result<HttpResponse> {
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = format!(
+        "SELECT {} FROM bans WHERE expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP ORDER BY id DESC",
+        BAN_COLUMNS
+    );
+    info!("Executing query: {}", query);
+    let bans: Vec<Ban> = conn.query(&query).await.map_err(ErrorInternalServerError)?;
+
+    let mut bans_html = String::new();
+    for ban in bans {
+        bans_html.push_str(&format!(
+            r#"<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td><form class="delete-form" action="/mod/bans/{}/lift" method="post"><button type="submit">Lift</button></form></td></tr>"#,
+            escape_html(&ban.target()),
+            ban.scope(),
+            escape_html(&ban.reason),
+            format_time(ban.created_at),
+            ban.expiry(),
+            ban.id
+        ));
+    }
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", moderation::nav(staff));
+    context.insert("MESSAGE", escape_html(message));
+    context.insert("BANS", bans_html);
+    context.insert("DURATIONS", duration_options());
+    let body = render_template("templates/mod_bans.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn show_bans(staff: Staff, pool: web::Data<Pool>) -> Result<HttpResponse> {
+    bans_page(&staff, &pool, "").await
+}
+
+// A CIDR range with its host bits cleared, or a single address as a range of one
+fn parse_range(target: &str) -> Option<IpNet> {
+    match target.parse::<IpNet>() {
+        Ok(range) => Some(range.trunc()),
+        Err(_) => target.parse::<IpAddr>().ok().map(|ip| IpNet::from(ip.to_canonical())),
+    }
+}
+
+// Ban an address or CIDR range typed in by a moderator
+pub async fn create_ban(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let target = form.get("ip_range").map(|target| target.trim()).unwrap_or("");
+    let Some(range) = parse_range(target) else {
+        return bans_page(&staff, &pool, "Enter an IP address or a CIDR range like 192.0.2.0/24.").await;
+    };
+
+    let reason = form.get("reason").map(|reason| reason.trim()).unwrap_or("");
+    if reason.is_empty() {
+        return bans_page(&staff, &pool, "A reason is required.").await;
+    }
+
+    let board = board_from_form(&form);
+    let expires_at = expiry_from_form(&form);
+    let details = ban_details(&range.to_string(), expires_at);
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    insert_ban(&mut conn, Some(staff.id), Some(range.to_string()), None, board.clone(), reason, expires_at)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    audit::record(&mut conn, &staff, Action::Ban, board.as_deref(), None, Some(reason), Some(details))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    bans_page(&staff, &pool, "Ban placed.").await
+}
+
+// Ban whoever made a post, using the address stored with it, and optionally delete the post in the same step.
+// Either way the post's reports count as handled.
+pub async fn ban_poster(
+    req: HttpRequest,
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<(String, i32)>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let (board_name, id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let table_name = format!("{}_files", board_name);
+
+    let reason = form.get("reason").map(|reason| reason.trim()).unwrap_or("");
+    if reason.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("A reason is required."));
+    }
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = format!("SELECT ip FROM {} WHERE id = ?", table_name);
+    info!("Executing query: {}", query);
+    let stored_ip: Option<Option<String>> = conn.exec_first(&query, (id,)).await.map_err(ErrorInternalServerError)?;
+    let Some(Some(stored_ip)) = stored_ip else {
+        return Ok(HttpResponse::BadRequest().body("No IP address is stored for this post."));
+    };
+
+    let (ip_range, ip_hash) = ban_target(stored_ip);
+    let global = form.contains_key("global");
+    let board = if global { None } else { Some(board_name.clone()) };
+    let expires_at = expiry_from_form(&form);
+    let mut details = ban_details(ip_range.as_deref().unwrap_or("hashed address"), expires_at);
+    if global {
+        details.push_str(", all boards");
+    }
+
+    insert_ban(&mut conn, Some(staff.id), ip_range, ip_hash, board, reason, expires_at)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    // Post bans are logged against the post's board even when they apply everywhere
+    audit::record(&mut conn, &staff, Action::Ban, Some(&board_name), Some(id), Some(reason), Some(details))
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    if form.contains_key("delete_post") {
+        deletion::delete_post(&mut conn, &board_name, id).await.map_err(ErrorInternalServerError)?;
+        audit::record(&mut conn, &staff, Action::DeletePost, Some(&board_name), Some(id), Some(reason), None)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    } else {
+        reports::dismiss(&mut conn, &board_name, id).await.map_err(ErrorInternalServerError)?;
+    }
+
+    Ok(moderation::redirect_back(&req))
+}
+
+pub async fn lift_ban(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let id = path.into_inner();
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let ban_query = format!("SELECT {} FROM bans WHERE id = ?", BAN_COLUMNS);
+    info!("Executing query: {}", ban_query);
+    let ban: Option<Ban> = conn.exec_first(&ban_query, (id,)).await.map_err(ErrorInternalServerError)?;
+    let Some(ban) = ban else {
+        return bans_page(&staff, &pool, "No such ban.").await;
+    };
+
+    // Lifted bans simply expire now, so they stay on record
+    let query = "UPDATE bans SET expires_at = CURRENT_TIMESTAMP WHERE id = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (id,)).await.map_err(ErrorInternalServerError)?;
+    audit::record(&mut conn, &staff, Action::LiftBan, ban.board.as_deref(), None, None, Some(format!("ban #{} on {}", ban.id, ban.target())))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/bans")).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(ip_range: Option<&str>, ip_hash: Option<&str>) -> Ban {
+        Ban {
+            id: 1,
+            ip_range: ip_range.map(str::to_string),
+            ip_hash: ip_hash.map(str::to_string),
+            board: None,
+            reason: String::new(),
+            created_at: 0,
+            expires_at: None,
+        }
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn single_address_ban() {
+        let ban = ban(Some("192.0.2.7/32"), None);
+        assert!(ban.matches(ip("192.0.2.7"), "192.0.2.7"));
+        assert!(!ban.matches(ip("192.0.2.8"), "192.0.2.8"));
+    }
+
+    #[test]
+    fn cidr_range_ban() {
+        let ban = ban(Some("198.51.100.0/24"), None);
+        assert!(ban.matches(ip("198.51.100.1"), ""));
+        assert!(ban.matches(ip("198.51.100.255"), ""));
+        assert!(!ban.matches(ip("198.51.101.0"), ""));
+        assert!(!ban.matches(ip("2001:db8::1"), ""));
+    }
+
+    #[test]
+    fn ipv6_range_ban() {
+        let ban = ban(Some("2001:db8:abcd::/48"), None);
+        assert!(ban.matches(ip("2001:db8:abcd:12::1"), ""));
+        assert!(!ban.matches(ip("2001:db8:abce::1"), ""));
+    }
+
+    #[test]
+    fn hashed_ip_ban() {
+        let ban = ban(None, Some("abc123"));
+        assert!(ban.matches(ip("203.0.113.5"), "abc123"));
+        assert!(!ban.matches(ip("203.0.113.5"), "def456"));
+    }
+
+    #[test]
+    fn typed_ranges_are_normalized() {
+        assert_eq!(parse_range("192.0.2.77/24").unwrap().to_string(), "192.0.2.0/24");
+        assert_eq!(parse_range("192.0.2.77").unwrap().to_string(), "192.0.2.77/32");
+        assert_eq!(parse_range("::ffff:192.0.2.77").unwrap().to_string(), "192.0.2.77/32");
+        assert_eq!(parse_range("2001:db8::1/32").unwrap().to_string(), "2001:db8::/32");
+        assert!(parse_range("192.0.2.0/33").is_none());
+        assert!(parse_range("example.com").is_none());
+        assert!(parse_range("").is_none());
+    }
+
+    #[test]
+    fn stored_ips_are_banned_by_range_or_hash() {
+        assert_eq!(ban_target("192.0.2.7".to_string()), (Some("192.0.2.7/32".to_string()), None));
+        assert_eq!(ban_target("9f86d081884c".to_string()), (None, Some("9f86d081884c".to_string())));
+    }
+
+    #[test]
+    fn unparsable_range_matches_nothing() {
+        let ban = ban(Some("not a range"), None);
+        assert!(!ban.matches(ip("203.0.113.5"), "203.0.113.5"));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::access::Visibility;
+use crate::approval::ApprovalMode;
+use crate::captcha::CaptchaMode;
+use crate::tripcode;
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, FromRowError, Row, Value};
+
+// File types a board may accept; anything else could be served as a page from /static
+pub const MEDIA_TYPES: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "mp4", "mp3", "webm"];
+
+// Per-board options stored in the `boards` table
+pub struct BoardSettings {
+    // Shown at the top of the board, the board name stands in for an empty title
+    pub title: String,
+    pub description: String,
+    pub rules: String,
+    pub nsfw: bool,
+    // Title and message limits in bytes, the same unit the database columns use
+    pub max_title_length: usize,
+    pub max_message_length: usize,
+    // Characters of an original post's message shown on the board index
+    pub preview_length: usize,
+    pub threads_per_page: usize,
+    pub allowed_file_types: Vec<String>,
+    pub op_file_required: bool,
+    pub text_only: bool,
+    pub default_name: String,
+    pub visibility: Visibility,
+    pub forced_anon: bool,
+    pub poster_ids: bool,
+    pub public_log: bool,
+    // Seconds a poster has to wait between two threads, two replies and two posts with a file
+    pub thread_cooldown: u32,
+    pub reply_cooldown: u32,
+    pub file_cooldown: u32,
+    pub captcha: CaptchaMode,
+    // Leading zero bits the proof-of-work must have, 0 when the board offers none
+    pub pow_difficulty: u32,
+    // Robot9000 mode: reject messages, and optionally images, that were posted on the board before
+    pub r9k: bool,
+    pub r9k_images: bool,
+    pub approval: ApprovalMode,
+}
+
+// Same values as the column defaults in schema.rs
+impl Default for BoardSettings {
+    fn default() -> Self {
+        BoardSettings {
+            title: String::new(),
+            description: String::new(),
+            rules: String::new(),
+            nsfw: false,
+            max_title_length: 30,
+            max_message_length: 50000,
+            preview_length: 2700,
+            threads_per_page: 30,
+            allowed_file_types: MEDIA_TYPES.iter().map(|file_type| file_type.to_string()).collect(),
+            op_file_required: false,
+            text_only: false,
+            default_name: tripcode::DEFAULT_NAME.to_string(),
+            visibility: Visibility::Public,
+            forced_anon: false,
+            poster_ids: false,
+            public_log: false,
+            thread_cooldown: 60,
+            reply_cooldown: 10,
+            file_cooldown: 20,
+            captcha: CaptchaMode::Off,
+            pow_difficulty: 0,
+            r9k: false,
+            r9k_images: false,
+            approval: ApprovalMode::Off,
+        }
+    }
+}
+
+fn settings_from_row(row: &mut Row) -> Option<BoardSettings> {
+    let captcha: String = row.take_opt("captcha")?.ok()?;
+    let approval: String = row.take_opt("approval")?.ok()?;
+    let visibility: String = row.take_opt("visibility")?.ok()?;
+    let rules: Option<String> = row.take_opt("rules")?.ok()?;
+    let allowed_file_types: String = row.take_opt("allowed_file_types")?.ok()?;
+    let max_title_length: u32 = row.take_opt("max_title_length")?.ok()?;
+    let max_message_length: u32 = row.take_opt("max_message_length")?.ok()?;
+    let preview_length: u32 = row.take_opt("preview_length")?.ok()?;
+    let threads_per_page: u32 = row.take_opt("threads_per_page")?.ok()?;
+    Some(BoardSettings {
+        title: row.take_opt("title")?.ok()?,
+        description: row.take_opt("description")?.ok()?,
+        rules: rules.unwrap_or_default(),
+        nsfw: row.take_opt("nsfw")?.ok()?,
+        max_title_length: max_title_length as usize,
+        max_message_length: max_message_length as usize,
+        preview_length: preview_length as usize,
+        threads_per_page: (threads_per_page as usize).max(1),
+        allowed_file_types: file_type_list(&allowed_file_types),
+        op_file_required: row.take_opt("op_file_required")?.ok()?,
+        text_only: row.take_opt("text_only")?.ok()?,
+        default_name: row.take_opt("default_name")?.ok()?,
+        visibility: Visibility::from_setting(&visibility),
+        forced_anon: row.take_opt("forced_anon")?.ok()?,
+        poster_ids: row.take_opt("poster_ids")?.ok()?,
+        public_log: row.take_opt("public_log")?.ok()?,
+        thread_cooldown: row.take_opt("thread_cooldown")?.ok()?,
+        reply_cooldown: row.take_opt("reply_cooldown")?.ok()?,
+        file_cooldown: row.take_opt("file_cooldown")?.ok()?,
+        captcha: CaptchaMode::from_setting(&captcha),
+        pow_difficulty: row.take_opt("pow_difficulty")?.ok()?,
+        r9k: row.take_opt("r9k")?.ok()?,
+        r9k_images: row.take_opt("r9k_images")?.ok()?,
+        approval: ApprovalMode::from_setting(&approval),
+    })
+}
+
+// Columns are looked up by name, there are too many settings for a tuple
+impl FromRow for BoardSettings {
+    fn from_row_opt(mut row: Row) -> Result<Self, FromRowError> {
+        match settings_from_row(&mut row) {
+            Some(settings) => Ok(settings),
+            None => Err(FromRowError(row)),
+        }
+    }
+}
+
+const SETTINGS_COLUMNS: &str = "title, description, rules, nsfw, max_title_length, max_message_length, preview_length, \
+    threads_per_page, allowed_file_types, op_file_required, text_only, default_name, visibility, forced_anon, poster_ids, public_log, \
+    thread_cooldown, reply_cooldown, file_cooldown, captcha, pow_difficulty, r9k, r9k_images, approval";
+
+fn file_type_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|file_type| file_type.trim().to_lowercase())
+        .filter(|file_type| !file_type.is_empty())
+        .collect()
+}
+
+impl BoardSettings {
+    // The title shown on the board's pages
+    pub fn display_title(&self, board_name: &str) -> String {
+        if self.title.is_empty() {
+            format!("/{}/", board_name)
+        } else {
+            format!("/{}/ - {}", board_name, self.title)
+        }
+    }
+
+    pub fn accepts_file(&self, file_extension: &str) -> bool {
+        !self.text_only && self.allowed_file_types.iter().any(|file_type| file_type == file_extension)
+    }
+}
+
+// Boards created before the `boards` table existed have no row yet and get the defaults
+pub async fn load_settings(conn: &mut Conn, board_name: &str) -> Result<BoardSettings, mysql_async::Error> {
+    let query = format!("SELECT {} FROM boards WHERE name = ?", SETTINGS_COLUMNS);
+    info!("Executing query: {}", query);
+    let settings: Option<BoardSettings> = conn.exec_first(&query, (board_name,)).await?;
+    Ok(settings.unwrap_or_default())
+}
+
+const MAX_NAME_LENGTH: usize = 32;
+
+// Names taken by the application's own routes
+pub const RESERVED_NAMES: &[&str] = &["mod", "static", "banned", "captcha", "settings", "overboard", "api"];
+
+// Why a name can't be used for a new board, if it can't. Names become table prefixes, so they are kept to plain ASCII
+// rather than everything `sanitize_board_name` lets through, which would allow look-alike names in other scripts.
+pub fn invalid_name(board_name: &str) -> Option<&'static str> {
+    if board_name.is_empty() || board_name.len() > MAX_NAME_LENGTH {
+        Some("Board names must be between 1 and 32 characters.")
+    } else if !board_name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()) {
+        Some("Board names may only contain lowercase letters a-z and digits.")
+    } else if RESERVED_NAMES.contains(&board_name) {
+        Some("That name is reserved.")
+    } else {
+        None
+    }
+}
+
+pub enum SettingKind {
+    Flag,
+    // Unsigned number between the given minimum and maximum
+    Number(u32, u32),
+    Choice(&'static [&'static str]),
+    // Comma separated values, each one of the given ones
+    Subset(&'static [&'static str]),
+    // Free text up to the given number of characters
+    Text(usize),
+}
+
+// Settings that can be changed, with the values each accepts
+pub const EDITABLE_SETTINGS: &[(&str, SettingKind)] = &[
+    ("title", SettingKind::Text(100)),
+    ("description", SettingKind::Text(255)),
+    ("rules", SettingKind::Text(10000)),
+    ("nsfw", SettingKind::Flag),
+    ("max_title_length", SettingKind::Number(1, 100)),
+    ("max_message_length", SettingKind::Number(1, 65535)),
+    ("preview_length", SettingKind::Number(0, 65535)),
+    ("threads_per_page", SettingKind::Number(1, 100)),
+    ("allowed_file_types", SettingKind::Subset(MEDIA_TYPES)),
+    ("op_file_required", SettingKind::Flag),
+    ("text_only", SettingKind::Flag),
+    ("default_name", SettingKind::Text(50)),
+    // Front page grouping; unlisted boards are left off the front page but stay reachable
+    ("category", SettingKind::Text(64)),
+    ("visibility", SettingKind::Choice(&["public", "unlisted", "private"])),
+    ("forced_anon", SettingKind::Flag),
+    ("poster_ids", SettingKind::Flag),
+    ("public_log", SettingKind::Flag),
+    ("thread_cooldown", SettingKind::Number(0, 86400)),
+    ("reply_cooldown", SettingKind::Number(0, 86400)),
+    ("file_cooldown", SettingKind::Number(0, 86400)),
+    ("captcha", SettingKind::Choice(&["off", "threads", "always"])),
+    ("pow_difficulty", SettingKind::Number(0, 32)),
+    ("r9k", SettingKind::Flag),
+    ("r9k_images", SettingKind::Flag),
+    ("approval", SettingKind::Choice(&["off", "threads", "files", "all"])),
+];
+
+// Check a setting and turn it into the value stored in its column
+pub fn parse_setting(key: &str, value: &str) -> Result<Value, String> {
+    let Some((_, kind)) = EDITABLE_SETTINGS.iter().find(|(name, _)| *name == key) else {
+        return Err(format!("Unknown setting {}.", key));
+    };
+    match kind {
+        SettingKind::Flag => match value {
+            "1" | "true" | "on" | "yes" => Ok(Value::from(true)),
+            "0" | "false" | "off" | "no" => Ok(Value::from(false)),
+            _ => Err(format!("{} must be true or false.", key)),
+        },
+        SettingKind::Number(min, max) => match value.parse::<u32>() {
+            Ok(number) if (*min..=*max).contains(&number) => Ok(Value::from(number)),
+            _ => Err(format!("{} must be a number from {} to {}.", key, min, max)),
+        },
+        SettingKind::Choice(options) => {
+            if options.contains(&value) {
+                Ok(Value::from(value))
+            } else {
+                Err(format!("{} must be one of {}.", key, options.join(", ")))
+            }
+        }
+        SettingKind::Subset(options) => {
+            let values = file_type_list(value);
+            match values.iter().find(|value| !options.contains(&value.as_str())) {
+                Some(unknown) => Err(format!("{} is not one of {}.", unknown, options.join(", "))),
+                None => Ok(Value::from(values.join(","))),
+            }
+        }
+        SettingKind::Text(max) => {
+            if value.chars().count() <= *max {
+                Ok(Value::from(value.trim()))
+            } else {
+                Err(format!("{} must be at most {} characters.", key, max))
+            }
+        }
+    }
+}
+
+// `key` must have been checked with `parse_setting`
+pub async fn save_setting(conn: &mut Conn, board_name: &str, key: &str, value: Value) -> Result<(), mysql_async::Error> {
+    let query = format!("UPDATE boards SET {} = ? WHERE name = ?", key);
+    info!("Executing query: {}", query);
+    conn.exec_drop(&query, (value, board_name)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_ascii_names_are_accepted() {
+        assert_eq!(invalid_name("b"), None);
+        assert_eq!(invalid_name("chess2"), None);
+        assert_eq!(invalid_name(&"a".repeat(MAX_NAME_LENGTH)), None);
+    }
+
+    #[test]
+    fn other_names_are_refused() {
+        for name in ["", "Chess", "my_board", "b-b", "b/../c", "bоard", "日本", "ｂ", "mod", "static"] {
+            assert!(invalid_name(name).is_some(), "{} was accepted", name);
+        }
+        assert!(invalid_name(&"a".repeat(MAX_NAME_LENGTH + 1)).is_some());
+    }
+
+    #[test]
+    fn settings_are_checked_against_their_kind() {
+        assert_eq!(parse_setting("forced_anon", "yes").unwrap(), Value::from(true));
+        assert!(parse_setting("forced_anon", "maybe").is_err());
+        assert!(parse_setting("pow_difficulty", "32").is_ok());
+        assert!(parse_setting("pow_difficulty", "33").is_err());
+        assert!(parse_setting("thread_cooldown", "-1").is_err());
+        assert!(parse_setting("captcha", "threads").is_ok());
+        assert!(parse_setting("captcha", "sometimes").is_err());
+        assert!(parse_setting("name", "b").is_err());
+        assert!(parse_setting("threads_per_page", "0").is_err());
+        assert!(parse_setting("threads_per_page", "100").is_ok());
+        assert_eq!(parse_setting("allowed_file_types", " PNG, jpg ").unwrap(), Value::from("png,jpg"));
+        assert!(parse_setting("allowed_file_types", "png,exe").is_err());
+        assert!(parse_setting("default_name", &"a".repeat(51)).is_err());
+        assert!(parse_setting("nsfw", "yes").is_ok());
+        assert!(parse_setting("owner_id", "1").is_err());
+    }
+
+    #[test]
+    fn files_follow_the_allowed_types() {
+        let mut settings = BoardSettings {
+            allowed_file_types: vec!["png".to_string(), "webm".to_string()],
+            ..BoardSettings::default()
+        };
+        assert!(settings.accepts_file("png"));
+        assert!(!settings.accepts_file("jpg"));
+        settings.text_only = true;
+        assert!(!settings.accepts_file("png"));
+    }
+}
@@ -0,0 +1,350 @@
+use crate::ratelimit::PostLimiter;
+use crate::{back_url, message_page};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use rand::{distributions::Alphanumeric, Rng};
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts::PI;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const ANSWER_LENGTH: usize = 6;
+// Challenges issued within one lifetime, beyond which new ones are refused until old ones expire
+const MAX_OPEN_CHALLENGES: usize = 10_000;
+// How many challenges one address may ask for at once, and how fast that allowance comes back
+const ISSUE_BURST: u32 = 30;
+const ISSUES_PER_MINUTE: u32 = 10;
+const WIDTH: usize = 220;
+const HEIGHT: usize = 70;
+const GLYPH_SCALE: usize = 5;
+
+// Letters and digits that can't be mistaken for one another (no I/1, O/0)
+const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+// 5x7 bitmap font for ALPHABET, one byte per row with the leftmost pixel in bit 4
+const GLYPHS: [[u8; 7]; 32] = [
+    [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11], // A
+    [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E], // B
+    [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E], // C
+    [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E], // D
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F], // E
+    [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10], // F
+    [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F], // G
+    [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11], // H
+    [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C], // J
+    [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // K
+    [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F], // L
+    [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11], // M
+    [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11], // N
+    [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10], // P
+    [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D], // Q
+    [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11], // R
+    [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E], // S
+    [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // T
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E], // U
+    [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04], // V
+    [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A], // W
+    [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11], // X
+    [0x11, 0x11, 0x0A, 0x04, 0x04, 0x04, 0x04], // Y
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F], // Z
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F], // 2
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E], // 3
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02], // 4
+    [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E], // 5
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E], // 6
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // 7
+    [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E], // 8
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C], // 9
+];
+
+// When a board asks for a CAPTCHA, stored in the `captcha` column of the `boards` table
+#[derive(Clone, Copy, PartialEq)]
+pub enum CaptchaMode {
+    Off,
+    Threads,
+    Always,
+}
+
+impl CaptchaMode {
+    pub fn from_setting(setting: &str) -> CaptchaMode {
+        match setting {
+            "always" => CaptchaMode::Always,
+            "threads" => CaptchaMode::Threads,
+            _ => CaptchaMode::Off,
+        }
+    }
+
+    pub fn required(self, is_reply: bool) -> bool {
+        match self {
+            CaptchaMode::Always => true,
+            CaptchaMode::Threads => !is_reply,
+            CaptchaMode::Off => false,
+        }
+    }
+}
+
+struct Challenge {
+    answer: String,
+    issued: Instant,
+}
+
+#[derive(Default)]
+struct Challenges {
+    by_token: HashMap<String, Challenge>,
+    // Tokens in the order they were issued, so expired ones are swept from the front
+    issued: VecDeque<(Instant, String)>,
+}
+
+// Open challenges by token, kept in memory. Each one can be solved once and expires after a few minutes.
+// Every address gets a limited allowance of new challenges, so no one can crowd out other posters.
+pub struct CaptchaStore {
+    challenges: Mutex<Challenges>,
+    limiter: PostLimiter,
+}
+
+impl Default for CaptchaStore {
+    fn default() -> CaptchaStore {
+        CaptchaStore {
+            challenges: Mutex::new(Challenges::default()),
+            limiter: PostLimiter::with_rate(ISSUE_BURST, ISSUES_PER_MINUTE),
+        }
+    }
+}
+
+impl CaptchaStore {
+    // A new challenge's token, or None when the address asked for too many or the store is full
+    pub fn issue(&self, ip: Option<IpAddr>) -> Option<String> {
+        if let Some(ip) = ip {
+            self.limiter.take(ip).ok()?;
+        }
+
+        let mut rng = rand::thread_rng();
+        let token: String = (&mut rng).sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+        let answer: String = (0..ANSWER_LENGTH)
+            .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+            .collect();
+
+        let mut challenges = self.challenges.lock().unwrap();
+        while let Some((issued, _)) = challenges.issued.front() {
+            if issued.elapsed() < CHALLENGE_LIFETIME {
+                break;
+            }
+            if let Some((_, expired)) = challenges.issued.pop_front() {
+                challenges.by_token.remove(&expired);
+            }
+        }
+        if challenges.issued.len() >= MAX_OPEN_CHALLENGES {
+            return None;
+        }
+
+        let now = Instant::now();
+        challenges.by_token.insert(token.clone(), Challenge { answer, issued: now });
+        challenges.issued.push_back((now, token.clone()));
+        Some(token)
+    }
+
+    pub fn answer(&self, token: &str) -> Option<String> {
+        self.challenges
+            .lock()
+            .unwrap()
+            .by_token
+            .get(token)
+            .filter(|challenge| challenge.issued.elapsed() < CHALLENGE_LIFETIME)
+            .map(|challenge| challenge.answer.clone())
+    }
+
+    // Check a solution; the challenge is used up whether the answer was right or not
+    pub fn verify(&self, token: &str, answer: &str) -> bool {
+        match self.challenges.lock().unwrap().by_token.remove(token) {
+            Some(challenge) => {
+                challenge.issued.elapsed() < CHALLENGE_LIFETIME && challenge.answer.eq_ignore_ascii_case(answer.trim())
+            },
+            None => false,
+        }
+    }
+}
+
+// The CAPTCHA part of a post form, empty when the board doesn't ask for one on this kind of post.
+// With a proof-of-work offered as the alternative the field may be left empty.
+pub fn form_field(store: &CaptchaStore, mode: CaptchaMode, is_reply: bool, pow_alternative: bool, ip: Option<IpAddr>) -> String {
+    if !mode.required(is_reply) {
+        return String::new();
+    }
+    let Some(token) = store.issue(ip) else {
+        return r#"<div class="captcha">Too many CAPTCHAs were requested, reload the page in a minute to get one.</div>"#.to_string();
+    };
+    let (placeholder, required) = if pow_alternative {
+        ("Type the characters above, or leave empty to run a proof-of-work instead", "")
+    } else {
+        ("Type the characters above", " required")
+    };
+    format!(
+        r#"<div class="captcha"><img src="/captcha/{token}" alt="CAPTCHA" width="{width}" height="{height}"><input type="hidden" name="captcha_token" value="{token}"><input type="text" name="captcha" maxlength="{length}" placeholder="{placeholder}" autocomplete="off"{required}></div>"#,
+        token = token,
+        width = WIDTH,
+        height = HEIGHT,
+        length = ANSWER_LENGTH,
+        placeholder = placeholder,
+        required = required
+    )
+}
+
+// Draw the answer into a grayscale buffer, bend it along two sine waves and cover it in noise
+fn render(answer: &str) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut text = vec![false; WIDTH * HEIGHT];
+
+    let glyph_width = 5 * GLYPH_SCALE;
+    let glyph_height = 7 * GLYPH_SCALE;
+    let spacing = (WIDTH - 20) / answer.len();
+    for (index, character) in answer.bytes().enumerate() {
+        let Some(glyph) = ALPHABET.iter().position(|c| *c == character).map(|i| &GLYPHS[i]) else {
+            continue;
+        };
+        let left = 10 + index * spacing + rng.gen_range(0..=spacing.saturating_sub(glyph_width).max(1));
+        let top = rng.gen_range(5..=HEIGHT - glyph_height - 5);
+        let slant: f64 = rng.gen_range(-0.35..0.35);
+
+        for y in 0..glyph_height {
+            let row = glyph[y / GLYPH_SCALE];
+            let shift = (slant * (y as f64 - glyph_height as f64 / 2.0)) as isize;
+            for x in 0..glyph_width {
+                if row & (0x10 >> (x / GLYPH_SCALE)) == 0 {
+                    continue;
+                }
+                let px = left as isize + x as isize + shift;
+                let py = top + y;
+                if (0..WIDTH as isize).contains(&px) && py < HEIGHT {
+                    text[py * WIDTH + px as usize] = true;
+                }
+            }
+        }
+    }
+
+    let (amplitude_x, amplitude_y) = (rng.gen_range(2.0..4.0), rng.gen_range(3.0..6.0));
+    let (period_x, period_y): (f64, f64) = (rng.gen_range(25.0..40.0), rng.gen_range(60.0..90.0));
+    let (phase_x, phase_y): (f64, f64) = (rng.gen_range(0.0..2.0 * PI), rng.gen_range(0.0..2.0 * PI));
+
+    let mut pixels = vec![0u8; WIDTH * HEIGHT];
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let source_x = x as f64 + amplitude_x * (2.0 * PI * y as f64 / period_x + phase_x).sin();
+            let source_y = y as f64 + amplitude_y * (2.0 * PI * x as f64 / period_y + phase_y).sin();
+            let (sx, sy) = (source_x.round() as isize, source_y.round() as isize);
+            let ink = (0..WIDTH as isize).contains(&sx)
+                && (0..HEIGHT as isize).contains(&sy)
+                && text[sy as usize * WIDTH + sx as usize];
+            pixels[y * WIDTH + x] = if ink { rng.gen_range(20..70) } else { rng.gen_range(200..=255) };
+        }
+    }
+
+    // Lines through the text so it can't be cut apart by simple thresholding
+    for _ in 0..4 {
+        let (x0, y0) = (0.0, rng.gen_range(0.0..HEIGHT as f64));
+        let (x1, y1) = (WIDTH as f64, rng.gen_range(0.0..HEIGHT as f64));
+        let shade = rng.gen_range(40..120);
+        for step in 0..WIDTH * 2 {
+            let t = step as f64 / (WIDTH * 2) as f64;
+            let x = (x0 + (x1 - x0) * t) as usize;
+            let y = (y0 + (y1 - y0) * t) as usize;
+            if x < WIDTH && y < HEIGHT {
+                pixels[y * WIDTH + x] = shade;
+            }
+        }
+    }
+    for _ in 0..WIDTH * HEIGHT / 12 {
+        let index = rng.gen_range(0..pixels.len());
+        pixels[index] = rng.gen_range(0..=255);
+    }
+
+    pixels
+}
+
+fn encode_png(pixels: &[u8]) -> Result<Vec<u8>, png::EncodingError> {
+    let mut png_data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, WIDTH as u32, HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+    Ok(png_data)
+}
+
+pub fn rejected(req: &HttpRequest) -> HttpResponse {
+    let back = back_url(req, "/");
+    HttpResponse::BadRequest()
+        .content_type("text/html")
+        .body(message_page("CAPTCHA", "The CAPTCHA or anti-spam check failed or has expired. Go back, reload the page and try again.", &back))
+}
+
+pub async fn captcha_image(
+    store: web::Data<CaptchaStore>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let Some(answer) = store.answer(&path.into_inner()) else {
+        return Ok(HttpResponse::NotFound().body("This CAPTCHA has expired, reload the page for a new one."));
+    };
+    let png_data = encode_png(&render(&answer)).map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .append_header(("Cache-Control", "no-store"))
+        .body(png_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correct_answer_passes_once() {
+        let store = CaptchaStore::default();
+        let token = store.issue(None).unwrap();
+        let answer = store.answer(&token).unwrap();
+        assert_eq!(answer.len(), ANSWER_LENGTH);
+        assert!(store.verify(&token, &format!(" {} ", answer.to_lowercase())));
+        assert!(!store.verify(&token, &answer));
+    }
+
+    #[test]
+    fn wrong_answer_uses_up_the_challenge() {
+        let store = CaptchaStore::default();
+        let token = store.issue(None).unwrap();
+        let answer = store.answer(&token).unwrap();
+        assert!(!store.verify(&token, "000000"));
+        assert!(!store.verify(&token, &answer));
+        assert!(!store.verify("unknown", &answer));
+    }
+
+    #[test]
+    fn issuing_is_limited_per_address() {
+        let store = CaptchaStore::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..ISSUE_BURST {
+            assert!(store.issue(Some(ip)).is_some());
+        }
+        assert!(store.issue(Some(ip)).is_none());
+        assert!(store.issue(Some("192.0.2.2".parse().unwrap())).is_some());
+    }
+
+    #[test]
+    fn full_store_refuses_instead_of_evicting() {
+        let store = CaptchaStore::default();
+        let first = store.issue(None).unwrap();
+        for _ in 1..MAX_OPEN_CHALLENGES {
+            assert!(store.issue(None).is_some());
+        }
+        assert!(store.issue(None).is_none());
+        assert!(store.answer(&first).is_some());
+    }
+
+    #[test]
+    fn modes() {
+        assert!(!CaptchaMode::from_setting("off").required(false));
+        assert!(CaptchaMode::from_setting("threads").required(false));
+        assert!(!CaptchaMode::from_setting("threads").required(true));
+        assert!(CaptchaMode::from_setting("always").required(true));
+        assert!(!CaptchaMode::from_setting("bogus").required(false));
+    }
+}
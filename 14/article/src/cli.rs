@@ -0,0 +1,288 @@
+use crate::access::{self, Visibility};
+use crate::{boards, deletion, posting_api, sanitize_board_name, schema};
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, Pool, Value};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+const USAGE: &str = "Usage:
+  my_project                                      start the server
+  my_project db init                              create the shared tables
+  my_project db migrate                           bring the shared tables and every board up to date
+  my_project board list
+  my_project board create <name> [setting=value ...]
+  my_project board set <name> setting=value ...
+  my_project board rename <old name> <new name>
+  my_project board password <name> [<password>]   set or, without a password, remove it
+  my_project board invite <name> [--uses=N] [--days=N]
+  my_project board delete <name> [--media=archive|delete] [--yes]
+  my_project apikey list
+  my_project apikey create <label> [--boards=a,b] [--per-minute=N]
+  my_project apikey revoke <id>
+
+Settings: title, description, rules, default_name, category (text), visibility (public/unlisted/private),
+nsfw, op_file_required, text_only, forced_anon, poster_ids, public_log, r9k, r9k_images (true/false),
+max_title_length (1-100), max_message_length (1-65535), preview_length, threads_per_page (1-100),
+allowed_file_types (comma separated, from jpg, jpeg, png, gif, webp, mp4, mp3, webm),
+thread_cooldown, reply_cooldown, file_cooldown (seconds), pow_difficulty (0-32),
+captcha (off/threads/always), approval (off/threads/files/all).
+Private boards are entered with the board password or an invite link.
+Deleted boards' media is moved to ./archive/<name>/ unless --media=delete is given.
+API keys without --boards may post on every board that isn't private, 10 posts a minute by default.";
+
+const ARCHIVE_DIR: &str = "./archive";
+
+// Board and database administration, run instead of the server when the binary gets arguments
+pub async fn run(pool: &Pool, args: &[String]) -> Result<(), String> {
+    let (flags, words): (Vec<&str>, Vec<&str>) = args.iter().map(String::as_str).partition(|arg| arg.starts_with('-'));
+    let known = matches!(
+        words.as_slice(),
+        ["db", "init" | "migrate"] | ["board", "list"] | ["board", "create", _, ..] | ["board", "set", _, _, ..] | ["board", "rename", _, _] | ["board", "delete", _]
+            | ["board", "password", _] | ["board", "password", _, _] | ["board", "invite", _]
+            | ["apikey", "list"] | ["apikey", "create", _] | ["apikey", "revoke", _]
+    );
+    if !known {
+        return Err(USAGE.to_string());
+    }
+
+    let mut conn = pool.get_conn().await.map_err(database_error)?;
+    match words.as_slice() {
+        ["db", "init"] => {
+            schema::init(&mut conn).await.map_err(database_error)?;
+            println!("Shared tables created, add boards with `board create`.");
+        }
+        ["db", "migrate"] => {
+            let migrated = schema::migrate(&mut conn).await.map_err(database_error)?;
+            println!("Shared tables and {} board(s) migrated.", migrated.len());
+        }
+        ["board", "list"] => list_boards(&mut conn).await?,
+        ["board", "create", name, settings @ ..] => create_board(&mut conn, name, settings).await?,
+        ["board", "set", name, settings @ ..] => set_board(&mut conn, name, settings).await?,
+        ["board", "rename", old_name, new_name] => rename_board(&mut conn, old_name, new_name).await?,
+        ["board", "delete", name] => delete_board(&mut conn, name, &flags).await?,
+        ["board", "password", name, password @ ..] => set_password(&mut conn, name, password.first().copied()).await?,
+        ["board", "invite", name] => create_invite(&mut conn, name, &flags).await?,
+        ["apikey", "list"] => list_keys(&mut conn).await?,
+        ["apikey", "create", label] => create_key(&mut conn, label, &flags).await?,
+        ["apikey", "revoke", id] => revoke_key(&mut conn, id).await?,
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
+
+fn database_error(err: mysql_async::Error) -> String {
+    format!("Database error: {}", err)
+}
+
+async fn board_exists(conn: &mut Conn, board_name: &str) -> Result<bool, String> {
+    let query = "SELECT COUNT(*) FROM boards WHERE name = ?";
+    info!("Executing query: {}", query);
+    let count: Option<i64> = conn.exec_first(query, (board_name,)).await.map_err(database_error)?;
+    Ok(count.unwrap_or(0) > 0)
+}
+
+// The name of a board that has to exist already
+async fn existing_board(conn: &mut Conn, name: &str) -> Result<String, String> {
+    let board_name = sanitize_board_name(name);
+    if board_name != name || !board_exists(conn, &board_name).await? {
+        return Err(format!("There is no board named {}.", name));
+    }
+    Ok(board_name)
+}
+
+// `setting=value` pairs, all checked before anything is written
+fn parse_settings(settings: &[&str]) -> Result<Vec<(String, Value)>, String> {
+    settings
+        .iter()
+        .map(|setting| {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Expected setting=value, got {}.", setting))?;
+            Ok((key.to_string(), boards::parse_setting(key, value)?))
+        })
+        .collect()
+}
+
+async fn list_boards(conn: &mut Conn) -> Result<(), String> {
+    let query = "SELECT name FROM boards ORDER BY name";
+    info!("Executing query: {}", query);
+    let names: Vec<String> = conn.query(query).await.map_err(database_error)?;
+
+    println!("{:<32} {:>8} {:>8}", "BOARD", "THREADS", "POSTS");
+    for name in names {
+        let count_query = format!("SELECT COUNT(*), COUNT(NULLIF(parent_id, 0)) FROM {}_files", name);
+        info!("Executing query: {}", count_query);
+        let counts: Option<(i64, i64)> = conn.query_first(&count_query).await.map_err(database_error)?;
+        let (posts, replies) = counts.unwrap_or((0, 0));
+        println!("{:<32} {:>8} {:>8}", format!("/{}/", name), posts - replies, posts);
+    }
+    Ok(())
+}
+
+async fn create_board(conn: &mut Conn, name: &str, settings: &[&str]) -> Result<(), String> {
+    if let Some(problem) = boards::invalid_name(name) {
+        return Err(problem.to_string());
+    }
+    if board_exists(conn, name).await? {
+        return Err(format!("Board {} already exists.", name));
+    }
+    let settings = parse_settings(settings)?;
+
+    schema::create_board(conn, name).await.map_err(database_error)?;
+    for (key, value) in settings {
+        boards::save_setting(conn, name, &key, value).await.map_err(database_error)?;
+    }
+    println!("Board /{}/ created.", name);
+    Ok(())
+}
+
+async fn set_board(conn: &mut Conn, name: &str, settings: &[&str]) -> Result<(), String> {
+    let board_name = existing_board(conn, name).await?;
+    let settings = parse_settings(settings)?;
+    for (key, value) in settings {
+        let visibility = (key == "visibility").then(|| Visibility::from_setting(&String::from_value(value.clone())));
+        boards::save_setting(conn, &board_name, &key, value).await.map_err(database_error)?;
+        if let Some(visibility) = visibility {
+            let moved = access::relocate_media(conn, &board_name, visibility).await.map_err(database_error)?;
+            if moved > 0 {
+                println!("{} file(s) moved to {}.", moved, access::media_dir(&board_name, visibility));
+            }
+        }
+    }
+    println!("Settings of /{}/ saved.", board_name);
+    Ok(())
+}
+
+async fn rename_board(conn: &mut Conn, old_name: &str, new_name: &str) -> Result<(), String> {
+    let old_name = existing_board(conn, old_name).await?;
+    if let Some(problem) = boards::invalid_name(new_name) {
+        return Err(problem.to_string());
+    }
+    if board_exists(conn, new_name).await? {
+        return Err(format!("Board {} already exists.", new_name));
+    }
+    schema::rename_board(conn, &old_name, new_name).await.map_err(database_error)?;
+    access::rename_media(conn, &old_name, new_name).await.map_err(database_error)?;
+    println!("Board /{}/ is now /{}/. Moderation log entries keep the old name.", old_name, new_name);
+    Ok(())
+}
+
+async fn set_password(conn: &mut Conn, name: &str, password: Option<&str>) -> Result<(), String> {
+    let board_name = existing_board(conn, name).await?;
+    access::set_password(conn, &board_name, password).await?;
+    match password {
+        Some(_) => println!("Password of /{}/ set, it is only asked for while the board is private.", board_name),
+        None => println!("Password of /{}/ removed, only invites let visitors in now.", board_name),
+    }
+    Ok(())
+}
+
+// A positive number given as `--name=N`
+fn number_flag(flags: &[&str], name: &str) -> Result<Option<u32>, String> {
+    match flags.iter().find_map(|flag| flag.strip_prefix(name)) {
+        None => Ok(None),
+        Some(value) => match value.parse() {
+            Ok(number) if number > 0 => Ok(Some(number)),
+            _ => Err(format!("{} expects a positive number, got {}.", name.trim_end_matches('='), value)),
+        },
+    }
+}
+
+async fn create_invite(conn: &mut Conn, name: &str, flags: &[&str]) -> Result<(), String> {
+    let board_name = existing_board(conn, name).await?;
+    let uses = number_flag(flags, "--uses=")?;
+    let days = number_flag(flags, "--days=")?;
+    let token = access::create_invite(conn, &board_name, uses, days).await.map_err(database_error)?;
+    println!("/{}/login?invite={}", board_name, token);
+    Ok(())
+}
+
+async fn list_keys(conn: &mut Conn) -> Result<(), String> {
+    let keys = posting_api::list_keys(conn).await.map_err(database_error)?;
+    println!("{:>4} {:<24} {:>8} {:<24} {:<16}", "ID", "LABEL", "PER MIN", "BOARDS", "LAST USED");
+    for (id, label, posts_per_minute, boards, revoked, last_used) in keys {
+        let boards = if revoked { "revoked".to_string() } else { boards };
+        println!("{:>4} {:<24} {:>8} {:<24} {:<16}", id, label, posts_per_minute, boards, last_used.unwrap_or_else(|| "never".to_string()));
+    }
+    Ok(())
+}
+
+async fn create_key(conn: &mut Conn, label: &str, flags: &[&str]) -> Result<(), String> {
+    if label.chars().count() > 64 {
+        return Err("Labels must be at most 64 characters.".to_string());
+    }
+    let posts_per_minute = number_flag(flags, "--per-minute=")?.unwrap_or(posting_api::DEFAULT_POSTS_PER_MINUTE);
+    let mut boards = Vec::new();
+    if let Some(list) = flags.iter().find_map(|flag| flag.strip_prefix("--boards=")) {
+        for name in list.split(',').filter(|name| !name.is_empty()) {
+            boards.push(existing_board(conn, name).await?);
+        }
+        if boards.is_empty() {
+            return Err("--boards needs at least one board.".to_string());
+        }
+    }
+    let token = posting_api::create_key(conn, label, &boards, posts_per_minute).await.map_err(database_error)?;
+    println!("{}", token);
+    eprintln!("Send it as `Authorization: Bearer <key>`. It is not stored and can't be shown again.");
+    Ok(())
+}
+
+async fn revoke_key(conn: &mut Conn, id: &str) -> Result<(), String> {
+    let key_id: i32 = id.parse().map_err(|_| format!("{} is not a key ID.", id))?;
+    if !posting_api::revoke_key(conn, key_id).await.map_err(database_error)? {
+        return Err(format!("There is no active key {}.", key_id));
+    }
+    println!("Key {} revoked.", key_id);
+    Ok(())
+}
+
+// Ask on the terminal, the answer has to be typed out in full
+fn confirm(question: &str, expected: &str) -> bool {
+    print!("{} Type {} to confirm: ", question, expected);
+    io::stdout().flush().ok();
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).is_ok() && answer.trim() == expected
+}
+
+async fn delete_board(conn: &mut Conn, name: &str, flags: &[&str]) -> Result<(), String> {
+    let board_name = existing_board(conn, name).await?;
+    let archive = match flags.iter().find_map(|flag| flag.strip_prefix("--media=")) {
+        None | Some("archive") => true,
+        Some("delete") => false,
+        Some(other) => return Err(format!("Unknown media option {}, use archive or delete.", other)),
+    };
+    let assume_yes = flags.iter().any(|flag| *flag == "--yes" || *flag == "-y");
+    if !assume_yes && !confirm(&format!("This deletes every post on /{}/.", board_name), &board_name) {
+        return Err("Nothing was deleted.".to_string());
+    }
+
+    let files_query = format!("SELECT file_path FROM {}_files WHERE file_path IS NOT NULL", board_name);
+    info!("Executing query: {}", files_query);
+    let files: Vec<String> = conn.query(&files_query).await.map_err(database_error)?;
+    schema::drop_board(conn, &board_name).await.map_err(database_error)?;
+
+    if archive && !files.is_empty() {
+        let archive_dir = Path::new(ARCHIVE_DIR).join(&board_name);
+        tokio::fs::create_dir_all(&archive_dir)
+            .await
+            .map_err(|err| format!("Could not create {}: {}", archive_dir.display(), err))?;
+        for file_path in &files {
+            let Some(file_name) = Path::new(file_path).file_name() else {
+                continue;
+            };
+            if let Err(err) = tokio::fs::rename(file_path, archive_dir.join(file_name)).await {
+                eprintln!("Could not archive {}: {}", file_path, err);
+            }
+        }
+        println!("Board /{}/ deleted, {} file(s) moved to {}.", board_name, files.len(), archive_dir.display());
+    } else {
+        for file_path in &files {
+            deletion::remove_media(file_path).await;
+        }
+        println!("Board /{}/ deleted along with {} file(s).", board_name, files.len());
+    }
+    // Only there if the board was ever private, and empty by now
+    tokio::fs::remove_dir(format!("{}/{}", access::PRIVATE_MEDIA_DIR, board_name)).await.ok();
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use log::{info, warn};
+use mysql_async::prelude::*;
+use mysql_async::Conn;
+
+// Delete a post together with its file and reports; deleting an original post removes the whole thread
+pub async fn delete_post(conn: &mut Conn, board_name: &str, id: i32) -> Result<(), mysql_async::Error> {
+    let table_name = format!("{}_files", board_name);
+    let posts_query = format!("SELECT id, file_path FROM {} WHERE id = ? OR parent_id = ?", table_name);
+    info!("Executing query: {}", posts_query);
+    let posts: Vec<(i32, Option<String>)> = conn.exec(&posts_query, (id, id)).await?;
+
+    let delete_query = format!("DELETE FROM {} WHERE id = ? OR parent_id = ?", table_name);
+    info!("Executing query: {}", delete_query);
+    conn.exec_drop(&delete_query, (id, id)).await?;
+
+    let reports_query = "DELETE FROM reports WHERE board = ? AND post_id = ?";
+    info!("Executing query: {}", reports_query);
+    conn.exec_batch(reports_query, posts.iter().map(|(post_id, _)| (board_name, *post_id))).await?;
+
+    for file_path in posts.into_iter().filter_map(|(_, file_path)| file_path) {
+        remove_media(&file_path).await;
+    }
+    Ok(())
+}
+
+// Remove only the attached file and keep the post itself
+pub async fn delete_file(conn: &mut Conn, board_name: &str, id: i32) -> Result<(), mysql_async::Error> {
+    let table_name = format!("{}_files", board_name);
+    let file_query = format!("SELECT file_path FROM {} WHERE id = ?", table_name);
+    info!("Executing query: {}", file_query);
+    let file_path: Option<Option<String>> = conn.exec_first(&file_query, (id,)).await?;
+
+    // Keep last_reply_at as is so removing a file does not bump the thread
+    let update_query = format!(
+        "UPDATE {} SET file_path = NULL, last_reply_at = last_reply_at WHERE id = ?",
+        table_name
+    );
+    info!("Executing query: {}", update_query);
+    conn.exec_drop(&update_query, (id,)).await?;
+
+    if let Some(Some(file_path)) = file_path {
+        remove_media(&file_path).await;
+    }
+    Ok(())
+}
+
+pub async fn remove_media(file_path: &str) {
+    if let Err(err) = tokio::fs::remove_file(file_path).await {
+        warn!("Could not remove {}: {}", file_path, err);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{escape_html, format_post_time, render_template, sanitize_board_name, visitor_timezone};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono_tz::Tz;
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, FromRowError, Pool, Row};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Counting posts on every board is too slow to repeat for each visitor
+const CACHE_LIFETIME: Duration = Duration::from_secs(60);
+// Heading for boards without a category, listed last
+const UNCATEGORIZED: &str = "Other";
+
+// A board as listed on the front page
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    title: String,
+    description: String,
+    nsfw: bool,
+    category: String,
+    posts: i64,
+    posts_last_hour: i64,
+    last_post_at: Option<i64>,
+}
+
+// The board list with its statistics, kept in memory between requests
+#[derive(Default)]
+pub struct DirectoryCache {
+    entries: Mutex<Option<(Instant, Vec<Entry>)>>,
+}
+
+impl DirectoryCache {
+    fn fresh(&self) -> Option<Vec<Entry>> {
+        match &*self.entries.lock().unwrap() {
+            Some((built, entries)) if built.elapsed() < CACHE_LIFETIME => Some(entries.clone()),
+            _ => None,
+        }
+    }
+
+    fn store(&self, entries: Vec<Entry>) {
+        *self.entries.lock().unwrap() = Some((Instant::now(), entries));
+    }
+}
+
+struct Stats {
+    board: String,
+    posts: i64,
+    posts_last_hour: i64,
+    last_post_at: Option<i64>,
+}
+
+impl FromRow for Stats {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        let (board, posts, posts_last_hour, last_post_at) = mysql_async::from_row_opt(row)?;
+        Ok(Stats { board, posts, posts_last_hour, last_post_at })
+    }
+}
+
+// Public boards ordered by category and name, with post counts from one query over all of them
+async fn load_entries(conn: &mut Conn) -> Result<Vec<Entry>, mysql_async::Error> {
+    let boards_query = "SELECT name, title, description, nsfw, category FROM boards WHERE visibility = 'public' ORDER BY category = '', category, name";
+    info!("Executing query: {}", boards_query);
+    let boards: Vec<(String, String, String, bool, String)> = conn.query(boards_query).await?;
+    if boards.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let selects: Vec<String> = boards
+        .iter()
+        .map(|(name, ..)| sanitize_board_name(name))
+        .map(|board| format!(
+            "(SELECT '{board}', COUNT(*), COUNT(CASE WHEN created_at > CURRENT_TIMESTAMP - INTERVAL 1 HOUR THEN 1 END), \
+             UNIX_TIMESTAMP(MAX(created_at)) FROM {board}_files WHERE pending = FALSE)",
+            board = board
+        ))
+        .collect();
+    let stats_query = selects.join(" UNION ALL ");
+    info!("Executing query: {}", stats_query);
+    let stats: Vec<Stats> = conn.query(&stats_query).await?;
+    let mut stats: HashMap<String, Stats> = stats.into_iter().map(|stats| (stats.board.clone(), stats)).collect();
+
+    Ok(boards
+        .into_iter()
+        .map(|(name, title, description, nsfw, category)| {
+            let stats = stats.remove(&name);
+            Entry {
+                posts: stats.as_ref().map_or(0, |stats| stats.posts),
+                posts_last_hour: stats.as_ref().map_or(0, |stats| stats.posts_last_hour),
+                last_post_at: stats.and_then(|stats| stats.last_post_at),
+                name,
+                title,
+                description,
+                nsfw,
+                category,
+            }
+        })
+        .collect())
+}
+
+fn render_entry(entry: &Entry, timezone: Tz) -> String {
+    let nsfw = if entry.nsfw { " <span class=\"nsfw-marker\">NSFW</span>" } else { "" };
+    let last_post = match entry.last_post_at {
+        Some(last_post_at) => format_post_time(last_post_at, timezone),
+        None => "No posts yet".to_string(),
+    };
+    format!(
+        "<tr><td><a href=\"/{name}\">/{name}/</a>{nsfw}</td><td>{title}</td><td>{description}</td><td>{posts}</td><td>{pph}</td><td>{last_post}</td></tr>",
+        name = entry.name,
+        nsfw = nsfw,
+        title = escape_html(&entry.title),
+        description = escape_html(&entry.description),
+        posts = entry.posts,
+        pph = entry.posts_last_hour,
+        last_post = last_post
+    )
+}
+
+// The front page: every public board, one table per category
+pub async fn front_page(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    cache: web::Data<DirectoryCache>,
+) -> Result<HttpResponse> {
+    let entries = match cache.fresh() {
+        Some(entries) => entries,
+        None => {
+            let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+            let entries = load_entries(&mut conn).await.map_err(ErrorInternalServerError)?;
+            cache.store(entries.clone());
+            entries
+        }
+    };
+
+    let timezone = visitor_timezone(&req);
+    let mut boards_html = String::new();
+    let mut current_category = None;
+    for entry in &entries {
+        if current_category != Some(&entry.category) {
+            if current_category.is_some() {
+                boards_html.push_str("</table>");
+            }
+            let heading = if entry.category.is_empty() { UNCATEGORIZED } else { &entry.category };
+            boards_html.push_str(&format!(
+                "<h2>{}</h2><table class=\"mod-table board-list\"><tr><th>Board</th><th>Title</th><th>Description</th><th>Posts</th><th>Posts/hour</th><th>Last post</th></tr>",
+                escape_html(heading)
+            ));
+            current_category = Some(&entry.category);
+        }
+        boards_html.push_str(&render_entry(entry, timezone));
+    }
+    if current_category.is_some() {
+        boards_html.push_str("</table>");
+    } else {
+        boards_html.push_str("<p>There are no boards yet.</p>");
+    }
+
+    let mut context = HashMap::new();
+    context.insert("BOARDS", boards_html);
+    context.insert("TIMEZONE", timezone.name().to_string());
+    let body = render_template("templates/front_page.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
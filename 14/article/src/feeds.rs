@@ -0,0 +1,230 @@
+use crate::access::BoardAccess;
+use crate::boards::{self, BoardSettings};
+use crate::config::Config;
+use crate::media_info;
+use crate::{escape_html, media_url, sanitize_board_name, Post, POST_COLUMNS};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::Pool;
+use regex::Regex;
+use std::sync::OnceLock;
+
+// Newest threads or replies listed in a feed
+const FEED_ENTRIES: usize = 50;
+
+fn timestamp(unix: i64) -> String {
+    DateTime::from_timestamp(unix, 0)
+        .unwrap_or_else(Utc::now)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn quote_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"&gt;&gt;(\d+)").unwrap())
+}
+
+// The post as HTML: the message escaped with its quotes linked, and the image when there is one
+fn entry_html(post: &Post, post_link: &str, thread_link: &str, file_link: Option<&str>) -> String {
+    let message = escape_html(&post.message).replace("\r\n", "\n");
+    let linked = quote_pattern().replace_all(&message, format!("<a href=\"{}#p$1\">&gt;&gt;$1</a>", thread_link).as_str());
+    let mut html = String::new();
+    if let Some(file_link) = file_link {
+        if media_info::mime_type(file_link).starts_with("image/") {
+            html.push_str(&format!("<p><a href=\"{}\"><img src=\"{}\" alt=\"\"></a></p>", post_link, file_link));
+        } else {
+            html.push_str(&format!("<p><a href=\"{}\">{}</a></p>", file_link, escape_html(file_link.rsplit('/').next().unwrap_or(file_link))));
+        }
+    }
+    html.push_str(&format!("<p>{}</p>", linked.replace('\n', "<br>")));
+    html
+}
+
+async fn entry_xml(post: &Post, base: &str, board_name: &str, thread_id: i32) -> String {
+    let thread_link = format!("{}/{}/post/{}", base, board_name, thread_id);
+    let post_link = format!("{}#p{}", thread_link, post.id);
+    let file_link = post.file_path.as_ref().map(|file_path| format!("{}{}", base, media_url(file_path)));
+    let title = if post.title.is_empty() { format!("No.{}", post.id) } else { post.title.clone() };
+    let mut author = post.name.clone();
+    if let Some(tripcode) = &post.tripcode {
+        author.push_str(tripcode);
+    }
+
+    let mut xml = String::from("<entry>");
+    xml.push_str(&format!("<id>{}</id>", escape_html(&post_link)));
+    xml.push_str(&format!("<title>{}</title>", escape_html(&title)));
+    xml.push_str(&format!("<link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>", escape_html(&post_link)));
+    if let (Some(file_path), Some(file_link)) = (&post.file_path, &file_link) {
+        // Files removed by staff since are left out rather than pointing readers at a 404
+        if let Some(info) = media_info::inspect(file_path).await {
+            xml.push_str(&format!(
+                "<link rel=\"enclosure\" type=\"{}\" length=\"{}\" href=\"{}\"/>",
+                media_info::mime_type(file_path),
+                info.size,
+                escape_html(file_link)
+            ));
+        }
+    }
+    xml.push_str(&format!("<published>{}</published>", timestamp(post.created_at)));
+    xml.push_str(&format!("<updated>{}</updated>", timestamp(post.created_at)));
+    xml.push_str(&format!("<author><name>{}</name></author>", escape_html(&author)));
+    let html = entry_html(post, &post_link, &thread_link, file_link.as_deref());
+    xml.push_str(&format!("<content type=\"html\">{}</content>", escape_html(&html)));
+    xml.push_str("</entry>");
+    xml
+}
+
+// An Atom document around entries that are already rendered
+fn feed(feed_url: &str, page_url: &str, title: &str, subtitle: &str, updated: i64, entries: &[String]) -> HttpResponse {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">");
+    xml.push_str(&format!("<id>{}</id>", escape_html(feed_url)));
+    xml.push_str(&format!("<title>{}</title>", escape_html(title)));
+    if !subtitle.is_empty() {
+        xml.push_str(&format!("<subtitle>{}</subtitle>", escape_html(subtitle)));
+    }
+    xml.push_str(&format!("<link rel=\"self\" type=\"application/atom+xml\" href=\"{}\"/>", escape_html(feed_url)));
+    xml.push_str(&format!("<link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>", escape_html(page_url)));
+    xml.push_str(&format!("<updated>{}</updated>", timestamp(updated)));
+    for entry in entries {
+        xml.push_str(entry);
+    }
+    xml.push_str("</feed>");
+    HttpResponse::Ok().content_type("application/atom+xml; charset=utf-8").body(xml)
+}
+
+async fn load_settings(conn: &mut mysql_async::Conn, board_name: &str) -> Result<Option<BoardSettings>> {
+    let query = "SELECT COUNT(*) FROM boards WHERE name = ?";
+    info!("Executing query: {}", query);
+    let count: Option<i64> = conn.exec_first(query, (board_name,)).await.map_err(ErrorInternalServerError)?;
+    if count.unwrap_or(0) == 0 {
+        return Ok(None);
+    }
+    boards::load_settings(conn, board_name).await.map(Some).map_err(ErrorInternalServerError)
+}
+
+// New threads on a board, newest first
+pub async fn board_feed(pool: web::Data<Pool>, config: web::Data<Config>, _access: BoardAccess, path: web::Path<String>) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let Some(settings) = load_settings(&mut conn, &board_name).await? else {
+        return Ok(HttpResponse::NotFound().body("No such board."));
+    };
+    let query = format!(
+        "SELECT {} FROM {}_files WHERE parent_id = 0 AND pending = FALSE ORDER BY id DESC LIMIT {}",
+        POST_COLUMNS, board_name, FEED_ENTRIES
+    );
+    info!("Executing query: {}", query);
+    let threads: Vec<Post> = conn.query(&query).await.map_err(ErrorInternalServerError)?;
+
+    // Feed readers fetch links from elsewhere, so every link is absolute. The origin comes from the config, never from
+    // the request's Host header.
+    let base = &config.public_url;
+    let mut entries = Vec::new();
+    for op in &threads {
+        entries.push(entry_xml(op, base, &board_name, op.id).await);
+    }
+    let updated = threads.iter().map(|op| op.created_at).max().unwrap_or_else(|| Utc::now().timestamp());
+    Ok(feed(
+        &format!("{}/{}/feed.atom", base, board_name),
+        &format!("{}/{}", base, board_name),
+        &settings.display_title(&board_name),
+        &settings.description,
+        updated,
+        &entries,
+    ))
+}
+
+// Replies to a thread, newest first
+pub async fn thread_feed(
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+    _access: BoardAccess,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse> {
+    let (board_name, thread_id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let Some(settings) = load_settings(&mut conn, &board_name).await? else {
+        return Ok(HttpResponse::NotFound().body("No such board."));
+    };
+    let op_query = format!("SELECT {} FROM {}_files WHERE id = ? AND parent_id = 0 AND pending = FALSE", POST_COLUMNS, board_name);
+    info!("Executing query: {}", op_query);
+    let op: Option<Post> = conn.exec_first(&op_query, (thread_id,)).await.map_err(ErrorInternalServerError)?;
+    let Some(op) = op else {
+        return Ok(HttpResponse::NotFound().body("Thread not found."));
+    };
+    let query = format!(
+        "SELECT {} FROM {}_files WHERE parent_id = ? AND pending = FALSE ORDER BY id DESC LIMIT {}",
+        POST_COLUMNS, board_name, FEED_ENTRIES
+    );
+    info!("Executing query: {}", query);
+    let replies: Vec<Post> = conn.exec(&query, (thread_id,)).await.map_err(ErrorInternalServerError)?;
+
+    let base = &config.public_url;
+    let mut entries = Vec::new();
+    for reply in &replies {
+        entries.push(entry_xml(reply, base, &board_name, thread_id).await);
+    }
+    let updated = replies.iter().map(|reply| reply.created_at).max().unwrap_or(op.created_at);
+    let title = if op.title.is_empty() { format!("No.{}", op.id) } else { op.title.clone() };
+    Ok(feed(
+        &format!("{}/{}/post/{}/feed.atom", base, board_name, thread_id),
+        &format!("{}/{}/post/{}", base, board_name, thread_id),
+        &format!("{} - {}", settings.display_title(&board_name), title),
+        "",
+        updated,
+        &entries,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(message: &str) -> Post {
+        Post {
+            id: 7,
+            post_id: String::new(),
+            poster_id: None,
+            name: "Anonymous".to_string(),
+            tripcode: None,
+            title: String::new(),
+            message: message.to_string(),
+            file_path: None,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn timestamps_are_rfc3339_in_utc() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(1700000000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn content_is_escaped_with_quotes_linked() {
+        let html = entry_html(&post("<b>hi</b>\r\n>>5"), "https://x.test/b/post/1#p7", "https://x.test/b/post/1", None);
+        assert_eq!(html, "<p>&lt;b&gt;hi&lt;/b&gt;<br><a href=\"https://x.test/b/post/1#p5\">&gt;&gt;5</a></p>");
+    }
+
+    #[test]
+    fn images_are_shown_and_other_files_linked() {
+        let image = entry_html(&post(""), "https://x.test/b/post/1#p7", "https://x.test/b/post/1", Some("https://x.test/static/a.png"));
+        assert!(image.starts_with("<p><a href=\"https://x.test/b/post/1#p7\"><img src=\"https://x.test/static/a.png\""));
+        let video = entry_html(&post(""), "https://x.test/b/post/1#p7", "https://x.test/b/post/1", Some("https://x.test/static/a.webm"));
+        assert!(video.starts_with("<p><a href=\"https://x.test/static/a.webm\">a.webm</a></p>"));
+    }
+
+    #[tokio::test]
+    async fn entries_escape_their_content_once_more_for_xml() {
+        let mut post = post("a & b");
+        post.title = "<t>".to_string();
+        let xml = entry_xml(&post, "https://x.test", "b", 1).await;
+        assert!(xml.contains("<id>https://x.test/b/post/1#p7</id>"));
+        assert!(xml.contains("<title>&lt;t&gt;</title>"));
+        assert!(xml.contains("<content type=\"html\">&lt;p&gt;a &amp;amp; b&lt;/p&gt;</content>"));
+        assert!(!xml.contains("enclosure"));
+    }
+}
@@ -0,0 +1,466 @@
+use crate::audit::{self, Action};
+use crate::bans;
+use crate::moderation::{self, Staff};
+use crate::{escape_html, render_template, sanitize_board_name};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpResponse, Result};
+use chrono::Utc;
+use log::{info, warn};
+use mysql_async::prelude::*;
+use mysql_async::{Conn, FromRowError, Pool, Row};
+use regex::{NoExpand, Regex, RegexBuilder};
+use std::collections::HashMap;
+
+// Compiled regexes larger than this are refused, so a filter can't exhaust memory
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+// What happens to a post that matches a filter
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterAction {
+    Replace,
+    Reject,
+    Hold,
+    Ban,
+}
+
+impl FilterAction {
+    const ALL: [FilterAction; 4] = [FilterAction::Replace, FilterAction::Reject, FilterAction::Hold, FilterAction::Ban];
+
+    fn key(self) -> &'static str {
+        match self {
+            FilterAction::Replace => "replace",
+            FilterAction::Reject => "reject",
+            FilterAction::Hold => "hold",
+            FilterAction::Ban => "ban",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            FilterAction::Replace => "Replace text",
+            FilterAction::Reject => "Reject post",
+            FilterAction::Hold => "Hold for review",
+            FilterAction::Ban => "Ban poster",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<FilterAction> {
+        FilterAction::ALL.iter().copied().find(|action| action.key() == key)
+    }
+}
+
+// A filter for one board, or for every board when `board` is empty
+pub struct Filter {
+    id: i32,
+    board: Option<String>,
+    pattern: String,
+    is_regex: bool,
+    action: FilterAction,
+    // Replacement text for replace filters, the message or ban reason for the others
+    replacement: String,
+    message: String,
+    ban_hours: i64,
+}
+
+impl FromRow for Filter {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        let (id, board, pattern, is_regex, action, replacement, message, ban_hours): (_, _, _, _, String, Option<String>, Option<String>, _) =
+            mysql_async::from_row_opt(row)?;
+        Ok(Filter {
+            id,
+            board,
+            pattern,
+            is_regex,
+            // Unknown actions are treated as the strictest that doesn't touch bans
+            action: FilterAction::from_key(&action).unwrap_or(FilterAction::Reject),
+            replacement: replacement.unwrap_or_default(),
+            message: message.unwrap_or_default(),
+            ban_hours,
+        })
+    }
+}
+
+const FILTER_COLUMNS: &str = "id, board, pattern, is_regex, action, replacement, message, ban_hours";
+
+impl Filter {
+    fn new() -> Filter {
+        Filter {
+            id: 0,
+            board: None,
+            pattern: String::new(),
+            is_regex: false,
+            action: FilterAction::Replace,
+            replacement: String::new(),
+            message: String::new(),
+            ban_hours: 0,
+        }
+    }
+
+    fn from_form(form: &HashMap<String, String>) -> Filter {
+        Filter {
+            id: form.get("id").and_then(|id| id.parse().ok()).unwrap_or(0),
+            board: form.get("board").map(|board| sanitize_board_name(board)).filter(|board| !board.is_empty()),
+            pattern: form.get("pattern").cloned().unwrap_or_default(),
+            is_regex: form.get("kind").map(String::as_str) == Some("regex"),
+            action: form.get("action").and_then(|action| FilterAction::from_key(action)).unwrap_or(FilterAction::Replace),
+            replacement: form.get("replacement").cloned().unwrap_or_default(),
+            message: form.get("message").map(|message| message.trim().to_string()).unwrap_or_default(),
+            ban_hours: form.get("duration").and_then(|hours| hours.parse().ok()).unwrap_or(0),
+        }
+    }
+
+    // Literal patterns match anywhere, ignoring case
+    fn regex(&self) -> std::result::Result<Regex, regex::Error> {
+        let pattern = if self.is_regex { self.pattern.clone() } else { regex::escape(&self.pattern) };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(!self.is_regex)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()
+    }
+
+    fn scope(&self) -> String {
+        match &self.board {
+            Some(board) => format!("/{}/", board),
+            None => "all boards".to_string(),
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!("{} {} on {}", self.action.label(), self.pattern, self.scope())
+    }
+}
+
+// The global filters and those of `board_name`, in the order they were created
+pub async fn load_filters(conn: &mut Conn, board_name: &str) -> std::result::Result<Vec<Filter>, mysql_async::Error> {
+    let query = format!("SELECT {} FROM filters WHERE board IS NULL OR board = ? ORDER BY id", FILTER_COLUMNS);
+    info!("Executing query: {}", query);
+    conn.exec(&query, (board_name,)).await
+}
+
+// What to do with a post after every filter ran
+pub enum Verdict {
+    Accept,
+    Hold,
+    Reject(String),
+    Ban { reason: String, board: Option<String>, expires_at: Option<i64> },
+}
+
+impl Verdict {
+    fn severity(&self) -> u8 {
+        match self {
+            Verdict::Accept => 0,
+            Verdict::Hold => 1,
+            Verdict::Reject(_) => 2,
+            Verdict::Ban { .. } => 3,
+        }
+    }
+}
+
+pub struct Filtered {
+    pub title: String,
+    pub message: String,
+    pub verdict: Verdict,
+}
+
+// Run the filters over a post. Replacements rewrite the title and message in order,
+// the other filters also look at the file name and the strictest match wins.
+pub fn apply(filters: &[Filter], title: &str, message: &str, filename: Option<&str>) -> Filtered {
+    let mut title = title.to_string();
+    let mut message = message.to_string();
+    let mut verdict = Verdict::Accept;
+
+    for filter in filters {
+        let regex = match filter.regex() {
+            Ok(regex) => regex,
+            Err(err) => {
+                warn!("Skipping filter {} with an invalid pattern: {}", filter.id, err);
+                continue;
+            },
+        };
+
+        if filter.action == FilterAction::Replace {
+            // Regex filters may refer to capture groups, literal replacements are taken as they are
+            if filter.is_regex {
+                title = regex.replace_all(&title, filter.replacement.as_str()).into_owned();
+                message = regex.replace_all(&message, filter.replacement.as_str()).into_owned();
+            } else {
+                title = regex.replace_all(&title, NoExpand(&filter.replacement)).into_owned();
+                message = regex.replace_all(&message, NoExpand(&filter.replacement)).into_owned();
+            }
+            continue;
+        }
+
+        let matched = regex.is_match(&title)
+            || regex.is_match(&message)
+            || filename.is_some_and(|filename| regex.is_match(filename));
+        if !matched {
+            continue;
+        }
+
+        let candidate = match filter.action {
+            FilterAction::Hold => Verdict::Hold,
+            FilterAction::Reject if filter.message.is_empty() => Verdict::Reject("Your post was rejected by a filter.".to_string()),
+            FilterAction::Reject => Verdict::Reject(filter.message.clone()),
+            FilterAction::Ban => Verdict::Ban {
+                reason: if filter.message.is_empty() { "Filtered content".to_string() } else { filter.message.clone() },
+                board: filter.board.clone(),
+                expires_at: (filter.ban_hours > 0).then(|| Utc::now().timestamp() + filter.ban_hours * 3600),
+            },
+            FilterAction::Replace => continue,
+        };
+        if candidate.severity() > verdict.severity() {
+            verdict = candidate;
+        }
+    }
+
+    Filtered { title, message, verdict }
+}
+
+fn render_form(filter: &Filter, sample: &str) -> String {
+    let action_options: String = FilterAction::ALL
+        .iter()
+        .map(|action| {
+            let selected = if *action == filter.action { " selected" } else { "" };
+            format!("<option value=\"{}\"{}>{}</option>", action.key(), selected, action.label())
+        })
+        .collect();
+    let (literal, regex) = if filter.is_regex { ("", " selected") } else { (" selected", "") };
+
+    format!(
+        r#"<form action="/mod/filters" method="post">
+            <input type="hidden" name="id" value="{id}">
+            <input type="text" name="board" value="{board}" placeholder="Board (empty for all boards)"><br>
+            <input type="text" name="pattern" value="{pattern}" placeholder="Word or regular expression" required><br>
+            <select name="kind"><option value="literal"{literal}>Literal, any case</option><option value="regex"{regex}>Regular expression</option></select>
+            <select name="action">{actions}</select><br>
+            <input type="text" name="replacement" value="{replacement}" placeholder="Replacement text (replace filters)"><br>
+            <input type="text" name="message" value="{message}" placeholder="Rejection message or ban reason"><br>
+            <select name="duration">{durations}</select><br>
+            <textarea name="sample" placeholder="Sample text to test the filter against">{sample}</textarea><br>
+            <button type="submit" name="intent" value="test">Test</button>
+            <button type="submit" name="intent" value="save">Save Filter</button>
+        </form>"#,
+        id = filter.id,
+        board = escape_html(filter.board.as_deref().unwrap_or("")),
+        pattern = escape_html(&filter.pattern),
+        literal = literal,
+        regex = regex,
+        actions = action_options,
+        replacement = escape_html(&filter.replacement),
+        message = escape_html(&filter.message),
+        durations = bans::duration_options_with(filter.ban_hours),
+        sample = escape_html(sample)
+    )
+}
+
+// The result of running a single, possibly unsaved, filter over the sample text
+fn test_result(filter: &Filter, sample: &str) -> String {
+    if let Err(err) = filter.regex() {
+        return format!("<p class=\"form-error\">Invalid pattern: {}</p>", escape_html(&err.to_string()));
+    }
+    let filtered = apply(std::slice::from_ref(filter), "", sample, None);
+    let outcome = match &filtered.verdict {
+        Verdict::Accept if filtered.message == sample => "No match, the post would be accepted as it is.".to_string(),
+        Verdict::Accept => "The post would be accepted with its text replaced.".to_string(),
+        Verdict::Hold => "The post would be held for review.".to_string(),
+        Verdict::Reject(message) => format!("The post would be rejected: {}", message),
+        Verdict::Ban { reason, .. } => format!("The poster would be banned: {}", reason),
+    };
+    format!(
+        "<div class=\"filter-test\"><p>{}</p><div class=\"post-message mod-message\">{}</div></div>",
+        escape_html(&outcome),
+        escape_html(&filtered.message)
+    )
+}
+
+async fn filters_page(staff: &Staff, pool: &Pool, filter: &Filter, sample: &str, message: &str, test: String) -> Result<HttpResponse> {
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = format!("SELECT {} FROM filters ORDER BY board IS NOT NULL, board, id", FILTER_COLUMNS);
+    info!("Executing query: {}", query);
+    let filters: Vec<Filter> = conn.query(&query).await.map_err(ErrorInternalServerError)?;
+
+    let mut filters_html = String::new();
+    for existing in &filters {
+        filters_html.push_str(&format!(
+            r#"<tr><td>{}</td><td><code>{}</code>{}</td><td>{}</td><td>{}</td><td><a href="/mod/filters?edit={}">Edit</a><form class="delete-form" action="/mod/filters/{}/delete" method="post"><button type="submit">Remove</button></form></td></tr>"#,
+            existing.scope(),
+            escape_html(&existing.pattern),
+            if existing.is_regex { " (regex)" } else { "" },
+            existing.action.label(),
+            escape_html(if existing.action == FilterAction::Replace { &existing.replacement } else { &existing.message }),
+            existing.id,
+            existing.id
+        ));
+    }
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", moderation::nav(staff));
+    context.insert("MESSAGE", escape_html(message));
+    context.insert("FORM", render_form(filter, sample));
+    context.insert("TEST_RESULT", test);
+    context.insert("FILTERS", filters_html);
+    let body = render_template("templates/mod_filters.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn show_filters(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let mut filter = Filter::new();
+    if let Some(id) = query.get("edit").and_then(|id| id.parse::<i32>().ok()) {
+        let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+        let filter_query = format!("SELECT {} FROM filters WHERE id = ?", FILTER_COLUMNS);
+        info!("Executing query: {}", filter_query);
+        if let Some(existing) = conn.exec_first(&filter_query, (id,)).await.map_err(ErrorInternalServerError)? {
+            filter = existing;
+        }
+    }
+    filters_page(&staff, &pool, &filter, "", "", String::new()).await
+}
+
+// Test a filter against the sample text, or save it when the save button was used
+pub async fn save_filter(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let filter = Filter::from_form(&form);
+    let sample = form.get("sample").map(String::as_str).unwrap_or("");
+
+    if form.get("intent").map(String::as_str) == Some("test") {
+        return filters_page(&staff, &pool, &filter, sample, "", test_result(&filter, sample)).await;
+    }
+    if filter.pattern.is_empty() {
+        return filters_page(&staff, &pool, &filter, sample, "A pattern is required.", String::new()).await;
+    }
+    if let Err(err) = filter.regex() {
+        let message = format!("Invalid pattern: {}", err);
+        return filters_page(&staff, &pool, &filter, sample, &message, String::new()).await;
+    }
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    if filter.id > 0 {
+        let query = "UPDATE filters SET board = ?, pattern = ?, is_regex = ?, action = ?, replacement = ?, message = ?, ban_hours = ? WHERE id = ?";
+        info!("Executing query: {}", query);
+        conn.exec_drop(query, (&filter.board, &filter.pattern, filter.is_regex, filter.action.key(), &filter.replacement, &filter.message, filter.ban_hours, filter.id))
+            .await
+            .map_err(ErrorInternalServerError)?;
+    } else {
+        let query = "INSERT INTO filters (board, pattern, is_regex, action, replacement, message, ban_hours, staff_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?)";
+        info!("Executing query: {}", query);
+        conn.exec_drop(query, (&filter.board, &filter.pattern, filter.is_regex, filter.action.key(), &filter.replacement, &filter.message, filter.ban_hours, staff.id))
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    audit::record(&mut conn, &staff, Action::SaveFilter, filter.board.as_deref(), None, None, Some(filter.summary()))
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    filters_page(&staff, &pool, &Filter::new(), "", "Filter saved.", String::new()).await
+}
+
+pub async fn remove_filter(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let id = path.into_inner();
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let filter_query = format!("SELECT {} FROM filters WHERE id = ?", FILTER_COLUMNS);
+    info!("Executing query: {}", filter_query);
+    let filter: Option<Filter> = conn.exec_first(&filter_query, (id,)).await.map_err(ErrorInternalServerError)?;
+    let Some(filter) = filter else {
+        return Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/filters")).finish());
+    };
+
+    let query = "DELETE FROM filters WHERE id = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (id,)).await.map_err(ErrorInternalServerError)?;
+    audit::record(&mut conn, &staff, Action::RemoveFilter, filter.board.as_deref(), None, None, Some(filter.summary()))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/filters")).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(pattern: &str, is_regex: bool, action: FilterAction, replacement: &str, message: &str) -> Filter {
+        Filter {
+            pattern: pattern.to_string(),
+            is_regex,
+            action,
+            replacement: replacement.to_string(),
+            message: message.to_string(),
+            ..Filter::new()
+        }
+    }
+
+    #[test]
+    fn literal_replacement_ignores_case_and_expansion() {
+        let filters = [filter("darn", false, FilterAction::Replace, "$1 heck", "")];
+        let filtered = apply(&filters, "Darn title", "darn it, DARN", None);
+        assert_eq!(filtered.title, "$1 heck title");
+        assert_eq!(filtered.message, "$1 heck it, $1 heck");
+        assert!(matches!(filtered.verdict, Verdict::Accept));
+    }
+
+    #[test]
+    fn literal_patterns_match_special_characters_as_text() {
+        let filters = [filter("a.b", false, FilterAction::Replace, "x", "")];
+        assert_eq!(apply(&filters, "", "a.b acb", None).message, "x acb");
+    }
+
+    #[test]
+    fn regex_replacement_uses_capture_groups() {
+        let filters = [filter(r"(\d+) dollars", true, FilterAction::Replace, "$$$1", "")];
+        assert_eq!(apply(&filters, "", "only 5 dollars", None).message, "only $5");
+    }
+
+    #[test]
+    fn replacements_run_before_later_filters_look() {
+        let filters = [
+            filter("spam", false, FilterAction::Replace, "ham", ""),
+            filter("spam", false, FilterAction::Reject, "", ""),
+        ];
+        assert!(matches!(apply(&filters, "", "spam", None).verdict, Verdict::Accept));
+    }
+
+    #[test]
+    fn strictest_match_wins() {
+        let filters = [
+            filter("buy", false, FilterAction::Hold, "", ""),
+            filter("cheap", false, FilterAction::Reject, "", "No ads."),
+            filter("pills", false, FilterAction::Ban, "", ""),
+        ];
+        assert!(matches!(apply(&filters, "", "buy now", None).verdict, Verdict::Hold));
+        match apply(&filters, "", "buy cheap", None).verdict {
+            Verdict::Reject(reason) => assert_eq!(reason, "No ads."),
+            _ => panic!("expected a rejection"),
+        }
+        match apply(&filters, "", "cheap pills", None).verdict {
+            Verdict::Ban { reason, board, expires_at } => {
+                assert_eq!(reason, "Filtered content");
+                assert_eq!(board, None);
+                assert_eq!(expires_at, None);
+            },
+            _ => panic!("expected a ban"),
+        }
+    }
+
+    #[test]
+    fn file_names_are_checked_but_not_rewritten() {
+        let filters = [filter(r"\.exe$", true, FilterAction::Reject, "", "")];
+        assert!(matches!(apply(&filters, "t", "m", Some("setup.exe")).verdict, Verdict::Reject(_)));
+        assert!(matches!(apply(&filters, "t", "m", Some("cat.png")).verdict, Verdict::Accept));
+    }
+
+    #[test]
+    fn invalid_patterns_are_skipped() {
+        let filters = [filter("(", true, FilterAction::Reject, "", "")];
+        assert!(matches!(apply(&filters, "(", "(", None).verdict, Verdict::Accept));
+    }
+}
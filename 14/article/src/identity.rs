@@ -0,0 +1,41 @@
+use crate::config::Config;
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+
+// The address of the visitor, taken from the proxy headers only when the config says to trust them
+pub fn client_ip(req: &HttpRequest, config: &Config) -> Option<IpAddr> {
+    let info = req.connection_info();
+    let addr = if config.trust_proxy { info.realip_remote_addr() } else { info.peer_addr() }?;
+
+    addr.parse::<IpAddr>()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|socket| socket.ip()))
+        .map(|ip| ip.to_canonical())
+}
+
+// The form an address is stored in next to a post: as is, or hashed with the server secret when the config asks for it
+pub fn stored_ip(ip: IpAddr, config: &Config) -> String {
+    if config.hash_ips {
+        let mut hasher = Sha256::new();
+        hasher.update(config.secret_key.as_bytes());
+        hasher.update(ip.to_string().as_bytes());
+        format!("{:x}", hasher.finalize())
+    } else {
+        ip.to_string()
+    }
+}
+
+// Short ID that stays the same for one poster within one thread, and changes every day
+pub fn poster_id(secret: &str, ip: IpAddr, thread_id: i32) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(secret.as_bytes());
+    hasher.update(Utc::now().format("%Y-%m-%d").to_string().as_bytes());
+    hasher.update(ip.to_string().as_bytes());
+    hasher.update(thread_id.to_be_bytes());
+    let encoded = URL_SAFE_NO_PAD.encode(hasher.finalize());
+    encoded[..8].to_string()
+}
@@ -0,0 +1,176 @@
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+// Enough of the file to find the dimensions of every image format we accept, JPEGs with large EXIF blocks aside
+const HEADER_BYTES: u64 = 64 * 1024;
+
+// What clients want to know about an attached file before fetching it
+pub struct MediaInfo {
+    pub size: u64,
+    // Only known for images
+    pub dimensions: Option<(u32, u32)>,
+}
+
+pub fn extension(file_path: &str) -> &str {
+    Path::new(file_path).extension().and_then(|ext| ext.to_str()).unwrap_or("")
+}
+
+pub fn mime_type(file_path: &str) -> &'static str {
+    match extension(file_path).to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+// Size and, for images, dimensions read from the file's header; `None` when the file is gone
+pub async fn inspect(file_path: &str) -> Option<MediaInfo> {
+    let file = tokio::fs::File::open(file_path).await.ok()?;
+    let size = file.metadata().await.ok()?.len();
+    let mut header = Vec::new();
+    file.take(HEADER_BYTES).read_to_end(&mut header).await.ok()?;
+    Some(MediaInfo { size, dimensions: image_dimensions(&header) })
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn le16(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u32)
+}
+
+fn le24(bytes: &[u8], at: usize) -> Option<u32> {
+    let bytes = bytes.get(at..at + 3)?;
+    Some(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16)
+}
+
+fn image_dimensions(header: &[u8]) -> Option<(u32, u32)> {
+    if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let width = u32::from_be_bytes(header.get(16..20)?.try_into().ok()?);
+        let height = u32::from_be_bytes(header.get(20..24)?.try_into().ok()?);
+        Some((width, height))
+    } else if header.starts_with(b"GIF8") {
+        Some((le16(header, 6)?, le16(header, 8)?))
+    } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WEBP") {
+        webp_dimensions(header)
+    } else if header.starts_with(&[0xFF, 0xD8]) {
+        jpeg_dimensions(header)
+    } else {
+        None
+    }
+}
+
+fn webp_dimensions(header: &[u8]) -> Option<(u32, u32)> {
+    match header.get(12..16)? {
+        b"VP8 " => Some((le16(header, 26)? & 0x3FFF, le16(header, 28)? & 0x3FFF)),
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(header.get(21..25)?.try_into().ok()?);
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        b"VP8X" => Some((le24(header, 24)? + 1, le24(header, 27)? + 1)),
+        _ => None,
+    }
+}
+
+// Walk the segments up to the first start-of-frame marker, which holds the dimensions
+fn jpeg_dimensions(header: &[u8]) -> Option<(u32, u32)> {
+    let mut at = 2;
+    loop {
+        if *header.get(at)? != 0xFF {
+            return None;
+        }
+        let marker = *header.get(at + 1)?;
+        match marker {
+            // Padding before a marker
+            0xFF => at += 1,
+            // Markers without a length
+            0x01 | 0xD0..=0xD9 => at += 2,
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                return Some((be16(header, at + 7)?, be16(header, at + 5)?));
+            }
+            _ => at += 2 + be16(header, at + 2)? as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(png::ColorType::Grayscale);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&vec![0; (width * height) as usize]).unwrap();
+        writer.finish().unwrap();
+        data
+    }
+
+    #[test]
+    fn png_dimensions() {
+        assert_eq!(image_dimensions(&png(3, 2)), Some((3, 2)));
+    }
+
+    #[test]
+    fn gif_dimensions() {
+        let header = b"GIF89a\x40\x01\xf0\x00\x00";
+        assert_eq!(image_dimensions(header), Some((320, 240)));
+    }
+
+    #[test]
+    fn jpeg_dimensions_after_other_segments() {
+        let mut header = vec![0xFF, 0xD8];
+        // An APP0 segment of 16 bytes, then padding and a baseline start-of-frame
+        header.extend([0xFF, 0xE0, 0x00, 0x10]);
+        header.extend([0; 14]);
+        header.extend([0xFF, 0xFF, 0xC0, 0x00, 0x11, 0x08, 0x01, 0xE0, 0x02, 0x80]);
+        assert_eq!(image_dimensions(&header), Some((640, 480)));
+    }
+
+    #[test]
+    fn jpeg_without_frame_in_header() {
+        assert_eq!(image_dimensions(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10]), None);
+        assert_eq!(image_dimensions(&[0xFF, 0xD8, 0x00]), None);
+    }
+
+    #[test]
+    fn webp_extended_dimensions() {
+        let mut header = b"RIFF\0\0\0\0WEBPVP8X".to_vec();
+        header.extend([0; 8]);
+        // Width and height minus one as 24 bit little endian numbers
+        header.extend([0x3F, 0x01, 0x00, 0xEF, 0x00, 0x00]);
+        assert_eq!(image_dimensions(&header), Some((320, 240)));
+    }
+
+    #[test]
+    fn webp_lossless_dimensions() {
+        let mut header = b"RIFF\0\0\0\0WEBPVP8L".to_vec();
+        header.extend([0; 4]);
+        header.push(0x2F);
+        let bits: u32 = 319 | (239 << 14);
+        header.extend(bits.to_le_bytes());
+        assert_eq!(image_dimensions(&header), Some((320, 240)));
+    }
+
+    #[test]
+    fn unknown_and_truncated_headers() {
+        assert_eq!(image_dimensions(b"not an image"), None);
+        assert_eq!(image_dimensions(b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(image_dimensions(b""), None);
+    }
+
+    #[test]
+    fn mime_types_follow_the_extension() {
+        assert_eq!(mime_type("./static/abc-Cat.JPG"), "image/jpeg");
+        assert_eq!(mime_type("clip.webm"), "video/webm");
+        assert_eq!(mime_type("song.mp3"), "audio/mpeg");
+        assert_eq!(mime_type("noextension"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,871 @@
+use crate::config::Config;
+use crate::audit::{self, Action};
+use crate::{back_url, bans, deletion, escape_html, format_post_time, identity, media_url, message_page, passwords, render_template, sanitize_board_name, visitor_timezone};
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie, SameSite};
+use actix_web::dev::Payload;
+use actix_web::error::{ErrorInternalServerError, InternalError};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, Result};
+use futures_util::future::LocalBoxFuture;
+use log::info;
+use mysql_async::prelude::*;
+use chrono_tz::Tz;
+use mysql_async::{Conn, FromRowError, Pool, Row};
+use rand::{distributions::Alphanumeric, Rng};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const SESSION_COOKIE: &str = "mod_session";
+const SESSION_DAYS: i64 = 7;
+// Failed logins allowed per address, and per staff account or private board across all addresses, within the window
+const MAX_LOGIN_FAILURES: usize = 5;
+const MAX_ACCOUNT_FAILURES: usize = 25;
+const LOGIN_FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+// Expired failures are only swept once this many keys are tracked
+const MAX_TRACKED_LOGINS: usize = 10_000;
+const RECENT_POSTS: usize = 100;
+
+// What a staff account may do, stored in the `role` column of the `staff` table
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    // Everything, including managing staff accounts
+    Admin,
+    // Every moderation tool on every board
+    GlobalMod,
+    // Creating boards and running the boards they own, including picking volunteers for them
+    Owner,
+    // Deleting posts on the boards assigned in `staff_boards`, nothing else
+    Janitor,
+}
+
+impl Role {
+    const ALL: [Role; 4] = [Role::Admin, Role::GlobalMod, Role::Owner, Role::Janitor];
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::GlobalMod => "global_mod",
+            Role::Owner => "owner",
+            Role::Janitor => "janitor",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Role::Admin => "Admin",
+            Role::GlobalMod => "Global moderator",
+            Role::Owner => "Board owner",
+            Role::Janitor => "Janitor",
+        }
+    }
+
+    // Unknown roles get the least access
+    fn from_key(key: &str) -> Role {
+        Role::ALL.iter().copied().find(|role| role.key() == key).unwrap_or(Role::Janitor)
+    }
+}
+
+// A logged in staff member, extracted from the session cookie.
+// Handlers taking this as an argument redirect to the login page when there is no valid session,
+// and answer 403 when the account's role does not allow the route (see `permitted`).
+pub struct Staff {
+    pub id: i32,
+    pub username: String,
+    pub role: Role,
+    // Boards a janitor is assigned to; empty for the other roles
+    pub boards: Vec<String>,
+    // Boards a board owner owns, from `boards.owner_id`; empty for the other roles
+    pub owned: Vec<String>,
+}
+
+impl Staff {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    // Admins and global moderators act on every board
+    pub fn is_global(&self) -> bool {
+        matches!(self.role, Role::Admin | Role::GlobalMod)
+    }
+
+    pub fn can_create_boards(&self) -> bool {
+        self.is_global() || self.role == Role::Owner
+    }
+
+    // Whether the board is within this account's reach
+    pub fn covers(&self, board_name: &str) -> bool {
+        self.owns(board_name) || self.boards.iter().any(|board| board == board_name)
+    }
+
+    // Whether the account may change the board's settings and volunteers
+    pub fn owns(&self, board_name: &str) -> bool {
+        self.is_global() || self.owned.iter().any(|board| board == board_name)
+    }
+}
+
+// The permission check behind every /mod route, keyed by the route pattern.
+// Routes not listed here need a global role, so a new route is closed to janitors until added.
+fn permitted(staff: &Staff, pattern: &str, board_name: Option<&str>) -> bool {
+    match pattern {
+        "/mod/staff" | "/mod/staff/{id}/delete" | "/mod/staff/{id}/role" => staff.is_admin(),
+        "/mod" => true,
+        "/mod/boards" => staff.can_create_boards(),
+        "/mod/boards/{board_name}"
+        | "/mod/boards/{board_name}/invite"
+        | "/mod/boards/{board_name}/volunteers"
+        | "/mod/boards/{board_name}/volunteers/{id}/remove" => board_name.is_some_and(|board_name| staff.owns(board_name)),
+        "/mod/{board_name}/post/{id}/delete" => board_name.is_some_and(|board_name| staff.covers(board_name)),
+        _ => staff.is_global(),
+    }
+}
+
+impl FromRequest for Staff {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let pool = req.app_data::<web::Data<Pool>>().cloned();
+        let token = req.cookie(SESSION_COOKIE).map(|cookie| cookie.value().to_string());
+        let pattern = req.match_pattern().unwrap_or_default();
+        let board_name = req.match_info().get("board_name").map(sanitize_board_name);
+
+        Box::pin(async move {
+            let (Some(pool), Some(token)) = (pool, token) else {
+                return Err(login_redirect());
+            };
+
+            let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+            let Some(staff) = session_staff(&mut conn, &token).await.map_err(ErrorInternalServerError)? else {
+                return Err(login_redirect());
+            };
+            if !permitted(&staff, &pattern, board_name.as_deref()) {
+                return Err(forbidden());
+            }
+            Ok(staff)
+        })
+    }
+}
+
+// The account behind a session token, if the session is still valid
+pub async fn session_staff(conn: &mut Conn, token: &str) -> Result<Option<Staff>, mysql_async::Error> {
+    let query = "SELECT staff.id, staff.username, staff.role FROM staff_sessions \
+        JOIN staff ON staff.id = staff_sessions.staff_id \
+        WHERE staff_sessions.token = ? AND staff_sessions.expires_at > CURRENT_TIMESTAMP";
+    info!("Executing query: {}", query);
+    let account: Option<(i32, String, String)> = conn.exec_first(query, (token,)).await?;
+    let Some((id, username, role)) = account else {
+        return Ok(None);
+    };
+
+    let role = Role::from_key(&role);
+    let boards = if role == Role::Janitor {
+        let boards_query = "SELECT board FROM staff_boards WHERE staff_id = ? ORDER BY board";
+        info!("Executing query: {}", boards_query);
+        conn.exec(boards_query, (id,)).await?
+    } else {
+        Vec::new()
+    };
+    let owned = if role == Role::Owner {
+        let owned_query = "SELECT name FROM boards WHERE owner_id = ? ORDER BY name";
+        info!("Executing query: {}", owned_query);
+        conn.exec(owned_query, (id,)).await?
+    } else {
+        Vec::new()
+    };
+    Ok(Some(Staff { id, username, role, boards, owned }))
+}
+
+fn login_redirect() -> actix_web::Error {
+    InternalError::from_response(
+        "login required",
+        HttpResponse::SeeOther().append_header(("Location", "/mod/login")).finish(),
+    )
+    .into()
+}
+
+fn forbidden() -> actix_web::Error {
+    let body = message_page("Not allowed", "Your staff role does not allow this.", "/mod");
+    InternalError::from_response(
+        "forbidden",
+        HttpResponse::Forbidden().content_type("text/html").body(body),
+    )
+    .into()
+}
+
+// What a failed login counts against. Clients whose address is unknown are still limited through the account or board.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum LoginKey {
+    Address(IpAddr),
+    Staff(String),
+    Board(String),
+}
+
+impl LoginKey {
+    fn max_failures(&self) -> usize {
+        match self {
+            LoginKey::Address(_) => MAX_LOGIN_FAILURES,
+            LoginKey::Staff(_) | LoginKey::Board(_) => MAX_ACCOUNT_FAILURES,
+        }
+    }
+}
+
+// Failed logins per address, account and private board, kept in memory; a restart clears them
+#[derive(Default)]
+pub struct LoginThrottle {
+    failures: Mutex<HashMap<LoginKey, Vec<Instant>>>,
+}
+
+impl LoginThrottle {
+    // Blocked as soon as any of the keys is over its limit
+    pub fn is_blocked(&self, keys: &[LoginKey]) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let mut blocked = false;
+        for key in keys {
+            let Some(recent) = failures.get_mut(key) else {
+                continue;
+            };
+            recent.retain(|at| at.elapsed() < LOGIN_FAILURE_WINDOW);
+            blocked |= recent.len() >= key.max_failures();
+            if recent.is_empty() {
+                failures.remove(key);
+            }
+        }
+        blocked
+    }
+
+    pub fn record_failure(&self, keys: &[LoginKey]) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED_LOGINS {
+            failures.retain(|_, recent| {
+                recent.retain(|at| at.elapsed() < LOGIN_FAILURE_WINDOW);
+                !recent.is_empty()
+            });
+        }
+        for key in keys {
+            failures.entry(key.clone()).or_default().push(Instant::now());
+        }
+    }
+
+    pub fn clear(&self, keys: &[LoginKey]) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in keys {
+            failures.remove(key);
+        }
+    }
+}
+
+// Links to the moderation tools the account may use, shown at the top of each panel page
+pub fn nav(staff: &Staff) -> String {
+    let mut links = String::from(r#"<a href="/mod">Recent Posts</a>"#);
+    if staff.is_global() {
+        links.push_str(r#"<a href="/mod/reports">Reports</a><a href="/mod/pending">Pending</a><a href="/mod/bans">Bans</a><a href="/mod/filters">Filters</a>"#);
+    }
+    if staff.can_create_boards() {
+        links.push_str(r#"<a href="/mod/boards">Boards</a>"#);
+    }
+    if staff.is_admin() {
+        links.push_str(r#"<a href="/mod/staff">Staff</a>"#);
+    }
+    if staff.is_global() {
+        links.push_str(r#"<a href="/mod/log">Log</a>"#);
+    }
+    format!(
+        r#"<div class="mod-nav">{}<span>Logged in as {} ({})</span><form action="/mod/logout" method="post"><button type="submit">Logout</button></form></div>"#,
+        links,
+        escape_html(&staff.username),
+        staff.role.label()
+    )
+}
+
+pub fn random_token(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+// Create the first account from the environment so a fresh install can log in at all
+pub async fn ensure_initial_staff(pool: &Pool, config: &Config) -> Result<(), mysql_async::Error> {
+    let Some((username, password)) = &config.initial_staff else {
+        return Ok(());
+    };
+
+    let mut conn = pool.get_conn().await?;
+    let query = "SELECT COUNT(*) FROM staff";
+    info!("Executing query: {}", query);
+    let staff_count: i64 = conn.query_first(query).await?.unwrap_or(0);
+    if staff_count > 0 {
+        return Ok(());
+    }
+
+    let password_hash = passwords::hash_password(password).expect("Unable to hash the initial staff password");
+    let insert_query = "INSERT INTO staff (username, password_hash, role) VALUES (?, ?, ?)";
+    info!("Executing query: {}", insert_query);
+    conn.exec_drop(insert_query, (username, password_hash, Role::Admin.key())).await?;
+    info!("Created initial staff account {}", username);
+    Ok(())
+}
+
+fn login_page(error: &str) -> HttpResponse {
+    let mut context = HashMap::new();
+    context.insert("ERROR", error.to_string());
+    let body = render_template("templates/mod_login.html", &context);
+    HttpResponse::Ok().content_type("text/html").body(body)
+}
+
+pub async fn show_login() -> Result<HttpResponse> {
+    Ok(login_page(""))
+}
+
+pub async fn login(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+    throttle: web::Data<LoginThrottle>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let username = form.get("username").map(|username| username.trim()).unwrap_or("");
+    let password = form.get("password").map(String::as_str).unwrap_or("");
+
+    // Usernames compare case-insensitively in the database, so the account key does too
+    let mut keys = vec![LoginKey::Staff(username.to_lowercase())];
+    keys.extend(identity::client_ip(&req, &config).map(LoginKey::Address));
+    if throttle.is_blocked(&keys) {
+        return Ok(HttpResponse::TooManyRequests().body("Too many failed logins, try again later."));
+    }
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = "SELECT id, password_hash FROM staff WHERE username = ?";
+    info!("Executing query: {}", query);
+    let account: Option<(i32, String)> = conn.exec_first(query, (username,)).await.map_err(ErrorInternalServerError)?;
+
+    let staff_id = match account {
+        Some((id, hash)) if passwords::verify_password(password, &hash) => id,
+        _ => {
+            throttle.record_failure(&keys);
+            return Ok(login_page("Wrong username or password."));
+        }
+    };
+    throttle.clear(&keys);
+
+    let token = random_token(64);
+    let session_query = format!(
+        "INSERT INTO staff_sessions (token, staff_id, expires_at) VALUES (?, ?, CURRENT_TIMESTAMP + INTERVAL {} DAY)",
+        SESSION_DAYS
+    );
+    info!("Executing query: {}", session_query);
+    conn.exec_drop(&session_query, (&token, staff_id)).await.map_err(ErrorInternalServerError)?;
+
+    let cookie = Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(CookieDuration::days(SESSION_DAYS))
+        .finish();
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod")).cookie(cookie).finish())
+}
+
+pub async fn logout(req: HttpRequest, pool: web::Data<Pool>) -> Result<HttpResponse> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+        let query = "DELETE FROM staff_sessions WHERE token = ?";
+        info!("Executing query: {}", query);
+        conn.exec_drop(query, (cookie.value(),)).await.map_err(ErrorInternalServerError)?;
+    }
+
+    let mut removal = Cookie::new(SESSION_COOKIE, "");
+    removal.set_path("/");
+    removal.make_removal();
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/login")).cookie(removal).finish())
+}
+
+// A post as shown to staff, from any board
+pub struct ModPost {
+    pub board: String,
+    pub id: i32,
+    parent_id: i32,
+    name: String,
+    tripcode: Option<String>,
+    title: String,
+    message: String,
+    file_path: Option<String>,
+    created_at: i64,
+}
+
+impl FromRow for ModPost {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        let (board, id, parent_id, name, tripcode, title, message, file_path, created_at) = mysql_async::from_row_opt(row)?;
+        Ok(ModPost { board, id, parent_id, name, tripcode, title, message, file_path, created_at })
+    }
+}
+
+// Columns selected for a `ModPost`, after the board name
+const MOD_POST_COLUMNS: &str = "id, parent_id, name, tripcode, title, message, file_path, UNIX_TIMESTAMP(created_at) AS created";
+
+pub async fn load_post(conn: &mut Conn, board_name: &str, id: i32) -> Result<Option<ModPost>, mysql_async::Error> {
+    let board_name = sanitize_board_name(board_name);
+    let query = format!("SELECT '{}', {} FROM {}_files WHERE id = ?", board_name, MOD_POST_COLUMNS, board_name);
+    info!("Executing query: {}", query);
+    conn.exec_first(&query, (id,)).await
+}
+
+// The post with its content escaped, followed by the delete and, for global staff, ban controls
+pub fn render_mod_post(post: &ModPost, staff: &Staff, timezone: Tz) -> String {
+    let thread_id = if post.parent_id == 0 { post.id } else { post.parent_id };
+    let mut poster = escape_html(&post.name);
+    if let Some(tripcode) = &post.tripcode {
+        poster.push_str(&format!(" <span class=\"post-tripcode\">{}</span>", escape_html(tripcode)));
+    }
+
+    let mut html = String::from("<div class=\"post\">");
+    html.push_str(&format!(
+        "<div class=\"post-id\"><a href=\"/{board}/post/{thread}#p{id}\">/{board}/ No.{id}</a> <span class=\"post-name\">{poster}</span></div>",
+        board = post.board, thread = thread_id, id = post.id, poster = poster
+    ));
+    html.push_str(&format_post_time(post.created_at, timezone));
+    html.push_str(&format!("<div class=\"post-title\">{}</div>", escape_html(&post.title)));
+    if let Some(file_path) = &post.file_path {
+        let file_name = file_path.rsplit('/').next().unwrap_or(file_path);
+        html.push_str(&format!("<a href=\"{}\">{}</a>", media_url(file_path), escape_html(file_name)));
+    }
+    html.push_str(&format!("<div class=\"post-message mod-message\">{}</div>", escape_html(&post.message)));
+    html.push_str(&format!(
+        r#"<form class="delete-form" action="/mod/{}/post/{}/delete" method="post"><input type="text" name="reason" placeholder="Reason (optional)"><label><input type="checkbox" name="file_only"> File only</label><button type="submit">Delete</button></form>"#,
+        post.board, post.id
+    ));
+    if staff.is_global() {
+        html.push_str(&format!(
+            r#"<form class="delete-form" action="/mod/{}/post/{}/ban" method="post"><input type="text" name="reason" placeholder="Ban reason" required><select name="duration">{}</select><label><input type="checkbox" name="global"> All boards</label><label><input type="checkbox" name="delete_post"> Delete post</label><button type="submit">Ban</button></form>"#,
+            post.board, post.id, bans::duration_options()
+        ));
+    }
+    html.push_str("</div>");
+    html
+}
+
+// Which end `posts_across_boards` takes its posts from
+#[derive(Clone, Copy)]
+pub enum Order {
+    NewestFirst,
+    OldestFirst,
+}
+
+impl Order {
+    fn direction(self) -> &'static str {
+        match self {
+            Order::NewestFirst => "DESC",
+            Order::OldestFirst => "ASC",
+        }
+    }
+}
+
+// Up to `limit` posts matching the SQL `condition` on any of `boards`, the newest or the oldest ones
+pub async fn posts_across_boards(
+    conn: &mut Conn,
+    boards: &[String],
+    condition: &str,
+    order: Order,
+    limit: usize,
+) -> Result<Vec<ModPost>, mysql_async::Error> {
+    let selects: Vec<String> = boards
+        .iter()
+        .map(|board| sanitize_board_name(board))
+        .map(|board| format!(
+            "(SELECT '{board}', {columns} FROM {board}_files WHERE {condition} ORDER BY id {direction} LIMIT {limit})",
+            board = board,
+            columns = MOD_POST_COLUMNS,
+            condition = condition,
+            direction = order.direction(),
+            limit = limit
+        ))
+        .collect();
+
+    if selects.is_empty() {
+        return Ok(Vec::new());
+    }
+    let posts_query = format!("{} ORDER BY created {} LIMIT {}", selects.join(" UNION ALL "), order.direction(), limit);
+    info!("Executing query: {}", posts_query);
+    conn.query(&posts_query).await
+}
+
+// Newest posts across every board the account covers, or a single one when `?board=` is given
+pub async fn recent_posts(
+    req: HttpRequest,
+    staff: Staff,
+    pool: web::Data<Pool>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let board_filter = query.get("board").map(|board| sanitize_board_name(board)).filter(|board| !board.is_empty());
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let boards_query = "SELECT name FROM boards ORDER BY name";
+    info!("Executing query: {}", boards_query);
+    let boards: Vec<String> = conn.query(boards_query).await.map_err(ErrorInternalServerError)?;
+    let boards: Vec<String> = boards.into_iter().filter(|board| staff.covers(board)).collect();
+
+    let shown: Vec<String> = boards
+        .iter()
+        .filter(|board| board_filter.as_ref().is_none_or(|filter| filter == *board))
+        .cloned()
+        .collect();
+    let posts = posts_across_boards(&mut conn, &shown, "TRUE", Order::NewestFirst, RECENT_POSTS).await.map_err(ErrorInternalServerError)?;
+
+    let timezone = visitor_timezone(&req);
+    let mut posts_html = String::new();
+    for post in posts {
+        posts_html.push_str(&render_mod_post(&post, &staff, timezone));
+    }
+    if posts_html.is_empty() {
+        posts_html.push_str("<p>No posts yet.</p>");
+    }
+
+    let board_options: String = boards
+        .iter()
+        .map(|board| {
+            let selected = if board_filter.as_deref() == Some(board.as_str()) { " selected" } else { "" };
+            format!("<option value=\"{}\"{}>/{}/</option>", escape_html(board), selected, escape_html(board))
+        })
+        .collect();
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", nav(&staff));
+    context.insert("BOARD_OPTIONS", board_options);
+    context.insert("POSTS", posts_html);
+    let body = render_template("templates/mod_panel.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn delete_post(
+    req: HttpRequest,
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<(String, i32)>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let (board_name, id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+
+    let action = if form.contains_key("file_only") {
+        deletion::delete_file(&mut conn, &board_name, id).await.map_err(ErrorInternalServerError)?;
+        Action::DeleteFile
+    } else {
+        deletion::delete_post(&mut conn, &board_name, id).await.map_err(ErrorInternalServerError)?;
+        Action::DeletePost
+    };
+    let reason = form.get("reason").map(String::as_str);
+    audit::record(&mut conn, &staff, action, Some(&board_name), Some(id), reason, None)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(redirect_back(&req))
+}
+
+// Send the moderator back to the page the action was taken from
+pub fn redirect_back(req: &HttpRequest) -> HttpResponse {
+    HttpResponse::SeeOther().append_header(("Location", back_url(req, "/mod"))).finish()
+}
+
+fn role_options(selected: Role) -> String {
+    Role::ALL
+        .iter()
+        .map(|role| {
+            let mark = if *role == selected { " selected" } else { "" };
+            format!("<option value=\"{}\"{}>{}</option>", role.key(), mark, role.label())
+        })
+        .collect()
+}
+
+// Board names from a comma or space separated list, as typed on the staff page
+fn parse_boards(list: &str) -> Vec<String> {
+    let mut boards: Vec<String> = list
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .map(sanitize_board_name)
+        .filter(|board| !board.is_empty())
+        .collect();
+    boards.sort();
+    boards.dedup();
+    boards
+}
+
+// Replace an account's board assignments; only janitors keep any, and boards that don't exist are skipped
+async fn assign_boards(conn: &mut Conn, staff_id: i32, role: Role, boards: &[String]) -> Result<(), mysql_async::Error> {
+    let clear_query = "DELETE FROM staff_boards WHERE staff_id = ?";
+    info!("Executing query: {}", clear_query);
+    conn.exec_drop(clear_query, (staff_id,)).await?;
+    if role != Role::Janitor {
+        return Ok(());
+    }
+
+    let insert_query = "INSERT IGNORE INTO staff_boards (staff_id, board) SELECT ?, name FROM boards WHERE name = ?";
+    info!("Executing query: {}", insert_query);
+    for board in boards {
+        conn.exec_drop(insert_query, (staff_id, board)).await?;
+    }
+    Ok(())
+}
+
+async fn staff_page(staff: &Staff, pool: &Pool, message: &str) -> Result<HttpResponse> {
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = "SELECT staff.id, staff.username, staff.role, \
+        GROUP_CONCAT(staff_boards.board ORDER BY staff_boards.board SEPARATOR ', ') \
+        FROM staff LEFT JOIN staff_boards ON staff_boards.staff_id = staff.id \
+        GROUP BY staff.id, staff.username, staff.role ORDER BY staff.username";
+    info!("Executing query: {}", query);
+    let accounts: Vec<(i32, String, String, Option<String>)> = conn.query(query).await.map_err(ErrorInternalServerError)?;
+
+    let mut accounts_html = String::new();
+    for (id, username, role, boards) in accounts {
+        let role = Role::from_key(&role);
+        let boards = boards.unwrap_or_default();
+        accounts_html.push_str(&format!("<tr><td>{}</td><td>", escape_html(&username)));
+        if id == staff.id {
+            // Admins can't demote or remove themselves, so there is always an admin left
+            accounts_html.push_str(role.label());
+        } else {
+            accounts_html.push_str(&format!(
+                r#"<form class="delete-form" action="/mod/staff/{}/role" method="post"><select name="role">{}</select><input type="text" name="boards" value="{}" placeholder="Janitor boards"><button type="submit">Save</button></form>"#,
+                id,
+                role_options(role),
+                escape_html(&boards)
+            ));
+        }
+        accounts_html.push_str("</td><td>");
+        if id != staff.id {
+            accounts_html.push_str(&format!(
+                r#"<form class="delete-form" action="/mod/staff/{}/delete" method="post"><button type="submit">Remove</button></form>"#,
+                id
+            ));
+        }
+        accounts_html.push_str("</td></tr>");
+    }
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", nav(staff));
+    context.insert("MESSAGE", escape_html(message));
+    context.insert("ACCOUNTS", accounts_html);
+    context.insert("ROLE_OPTIONS", role_options(Role::Janitor));
+    let body = render_template("templates/mod_staff.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn show_staff(staff: Staff, pool: web::Data<Pool>) -> Result<HttpResponse> {
+    staff_page(&staff, &pool, "").await
+}
+
+pub async fn add_staff(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let username = form.get("username").map(|username| username.trim()).unwrap_or("");
+    let password = form.get("password").map(String::as_str).unwrap_or("");
+    let role = Role::from_key(form.get("role").map(String::as_str).unwrap_or(""));
+    let boards = parse_boards(form.get("boards").map(String::as_str).unwrap_or(""));
+
+    if username.is_empty() || username.chars().count() > 32 {
+        return staff_page(&staff, &pool, "Usernames must be between 1 and 32 characters.").await;
+    }
+    if password.chars().count() < 8 {
+        return staff_page(&staff, &pool, "Passwords must be at least 8 characters.").await;
+    }
+
+    let password_hash = passwords::hash_password(password).map_err(ErrorInternalServerError)?;
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = "INSERT IGNORE INTO staff (username, password_hash, role) VALUES (?, ?, ?)";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (username, password_hash, role.key())).await.map_err(ErrorInternalServerError)?;
+
+    let message = if conn.affected_rows() == 0 {
+        "That username is already taken."
+    } else {
+        let new_id = conn.last_insert_id().unwrap_or(0) as i32;
+        assign_boards(&mut conn, new_id, role, &boards).await.map_err(ErrorInternalServerError)?;
+        let details = format!("{} as {}", username, describe_role(role, &boards));
+        audit::record(&mut conn, &staff, Action::AddStaff, None, None, None, Some(details))
+            .await
+            .map_err(ErrorInternalServerError)?;
+        "Account created."
+    };
+    staff_page(&staff, &pool, message).await
+}
+
+pub async fn remove_staff(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<i32>,
+) -> Result<HttpResponse> {
+    let id = path.into_inner();
+    if id == staff.id {
+        return staff_page(&staff, &pool, "You cannot remove your own account.").await;
+    }
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let name_query = "SELECT username FROM staff WHERE id = ?";
+    info!("Executing query: {}", name_query);
+    let username: Option<String> = conn.exec_first(name_query, (id,)).await.map_err(ErrorInternalServerError)?;
+    let Some(username) = username else {
+        return staff_page(&staff, &pool, "No such account.").await;
+    };
+
+    let query = "DELETE FROM staff WHERE id = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (id,)).await.map_err(ErrorInternalServerError)?;
+    // Their boards stay and are left to global staff
+    let boards_query = "UPDATE boards SET owner_id = NULL WHERE owner_id = ?";
+    info!("Executing query: {}", boards_query);
+    conn.exec_drop(boards_query, (id,)).await.map_err(ErrorInternalServerError)?;
+    audit::record(&mut conn, &staff, Action::RemoveStaff, None, None, None, Some(username))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/staff")).finish())
+}
+
+fn describe_role(role: Role, boards: &[String]) -> String {
+    if role == Role::Janitor {
+        let boards: Vec<String> = boards.iter().map(|board| format!("/{}/", board)).collect();
+        format!("{} of {}", role.label(), if boards.is_empty() { "no boards".to_string() } else { boards.join(", ") })
+    } else {
+        role.label().to_string()
+    }
+}
+
+pub async fn set_role(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<i32>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let id = path.into_inner();
+    if id == staff.id {
+        return staff_page(&staff, &pool, "You cannot change your own role.").await;
+    }
+    let role = Role::from_key(form.get("role").map(String::as_str).unwrap_or(""));
+    let boards = parse_boards(form.get("boards").map(String::as_str).unwrap_or(""));
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let name_query = "SELECT username FROM staff WHERE id = ?";
+    info!("Executing query: {}", name_query);
+    let username: Option<String> = conn.exec_first(name_query, (id,)).await.map_err(ErrorInternalServerError)?;
+    let Some(username) = username else {
+        return staff_page(&staff, &pool, "No such account.").await;
+    };
+
+    let query = "UPDATE staff SET role = ? WHERE id = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (role.key(), id)).await.map_err(ErrorInternalServerError)?;
+    assign_boards(&mut conn, id, role, &boards).await.map_err(ErrorInternalServerError)?;
+
+    let details = format!("{} to {}", username, describe_role(role, &boards));
+    audit::record(&mut conn, &staff, Action::SetRole, None, None, None, Some(details))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/staff")).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_blocks_an_address_after_too_many_failures() {
+        let throttle = LoginThrottle::default();
+        let keys = [LoginKey::Staff("admin".to_string()), LoginKey::Address("192.0.2.1".parse().unwrap())];
+        for _ in 0..MAX_LOGIN_FAILURES {
+            assert!(!throttle.is_blocked(&keys));
+            throttle.record_failure(&keys);
+        }
+        assert!(throttle.is_blocked(&keys));
+        // Another address may still try the same account
+        assert!(!throttle.is_blocked(&[LoginKey::Staff("admin".to_string()), LoginKey::Address("192.0.2.2".parse().unwrap())]));
+        throttle.clear(&keys);
+        assert!(!throttle.is_blocked(&keys));
+    }
+
+    #[test]
+    fn throttle_limits_an_account_without_a_known_address() {
+        let throttle = LoginThrottle::default();
+        let keys = [LoginKey::Staff("admin".to_string())];
+        for _ in 0..MAX_ACCOUNT_FAILURES {
+            assert!(!throttle.is_blocked(&keys));
+            throttle.record_failure(&keys);
+        }
+        assert!(throttle.is_blocked(&keys));
+        assert!(throttle.is_blocked(&[LoginKey::Staff("admin".to_string()), LoginKey::Address("192.0.2.3".parse().unwrap())]));
+        assert!(!throttle.is_blocked(&[LoginKey::Staff("mod".to_string())]));
+    }
+
+    #[test]
+    fn board_logins_are_counted_apart_from_staff_logins() {
+        let throttle = LoginThrottle::default();
+        let address = LoginKey::Address("192.0.2.4".parse().unwrap());
+        let keys = [LoginKey::Board("secret".to_string()), address.clone()];
+        for _ in 0..MAX_LOGIN_FAILURES {
+            throttle.record_failure(&keys);
+        }
+        assert!(throttle.is_blocked(&keys));
+        assert!(!throttle.is_blocked(&[LoginKey::Board("secret".to_string())]));
+        assert!(!throttle.is_blocked(&[LoginKey::Staff("secret".to_string())]));
+        throttle.clear(&keys);
+        assert!(!throttle.is_blocked(&[address]));
+    }
+
+    #[test]
+    fn throttle_only_tracks_failing_keys() {
+        let throttle = LoginThrottle::default();
+        for last in 0..=255u8 {
+            assert!(!throttle.is_blocked(&[LoginKey::Address(IpAddr::from([192, 0, 2, last]))]));
+        }
+        assert!(throttle.failures.lock().unwrap().is_empty());
+    }
+
+    fn staff(role: Role, boards: &[&str]) -> Staff {
+        Staff {
+            id: 1,
+            username: "staff".to_string(),
+            role,
+            boards: boards.iter().map(|board| board.to_string()).collect(),
+            owned: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn janitors_only_delete_on_their_boards() {
+        let janitor = staff(Role::Janitor, &["b"]);
+        assert!(permitted(&janitor, "/mod", None));
+        assert!(permitted(&janitor, "/mod/{board_name}/post/{id}/delete", Some("b")));
+        assert!(!permitted(&janitor, "/mod/{board_name}/post/{id}/delete", Some("g")));
+        assert!(!permitted(&janitor, "/mod/bans", None));
+        assert!(!permitted(&janitor, "/mod/staff", None));
+    }
+
+    #[test]
+    fn only_admins_manage_staff() {
+        let global_mod = staff(Role::GlobalMod, &[]);
+        assert!(permitted(&global_mod, "/mod/bans", None));
+        assert!(permitted(&global_mod, "/mod/{board_name}/post/{id}/delete", Some("g")));
+        assert!(!permitted(&global_mod, "/mod/staff/{id}/role", None));
+        assert!(permitted(&staff(Role::Admin, &[]), "/mod/staff/{id}/role", None));
+    }
+
+    #[test]
+    fn owners_run_only_their_own_boards() {
+        let owner = Staff { owned: vec!["chess".to_string()], ..staff(Role::Owner, &[]) };
+        assert!(permitted(&owner, "/mod/boards", None));
+        assert!(permitted(&owner, "/mod/boards/{board_name}/volunteers", Some("chess")));
+        assert!(!permitted(&owner, "/mod/boards/{board_name}/volunteers", Some("b")));
+        assert!(permitted(&owner, "/mod/{board_name}/post/{id}/delete", Some("chess")));
+        assert!(!permitted(&owner, "/mod/bans", None));
+        assert!(!permitted(&staff(Role::Janitor, &["chess"]), "/mod/boards", None));
+    }
+
+    #[test]
+    fn unknown_roles_get_the_least_access() {
+        assert!(Role::from_key("janitor") == Role::Janitor);
+        assert!(Role::from_key("global_mod") == Role::GlobalMod);
+        assert!(Role::from_key("owner") == Role::Owner);
+        assert!(Role::from_key("moderator") == Role::Janitor);
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{back_url, escape_html, format_post_time, media_html, media_info, media_url, render_poster, render_template, sanitize_board_name, visitor_timezone, Post, POST_COLUMNS};
+use actix_web::cookie::{time::Duration as CookieDuration, Cookie};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use chrono_tz::Tz;
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, FromRowError, Pool, Row};
+use std::collections::HashMap;
+
+const FILTER_COOKIE: &str = "overboard";
+const THREADS_PER_PAGE: usize = 15;
+const CATALOG_PER_PAGE: usize = 60;
+// Every board is asked for enough threads to fill the last page, so the depth is capped
+const MAX_PAGES: usize = 10;
+const PREVIEW_LENGTH: usize = 1000;
+const CATALOG_PREVIEW_LENGTH: usize = 150;
+
+// Which boards a visitor wants on the overboard, remembered in a cookie as `exclude:a,b` or `include:a,b`
+enum BoardFilter {
+    Exclude(Vec<String>),
+    Include(Vec<String>),
+}
+
+impl BoardFilter {
+    fn from_request(req: &HttpRequest) -> BoardFilter {
+        let cookie = req.cookie(FILTER_COOKIE);
+        let value = cookie.as_ref().map(|cookie| cookie.value()).unwrap_or("");
+        match value.split_once(':') {
+            Some(("include", boards)) => BoardFilter::Include(board_list(boards)),
+            Some(("exclude", boards)) => BoardFilter::Exclude(board_list(boards)),
+            _ => BoardFilter::Exclude(Vec::new()),
+        }
+    }
+
+    fn shows(&self, board_name: &str) -> bool {
+        match self {
+            BoardFilter::Exclude(boards) => !boards.iter().any(|board| board == board_name),
+            BoardFilter::Include(boards) => boards.iter().any(|board| board == board_name),
+        }
+    }
+
+    fn boards(&self) -> &[String] {
+        match self {
+            BoardFilter::Exclude(boards) | BoardFilter::Include(boards) => boards,
+        }
+    }
+
+    fn mode(&self) -> &'static str {
+        match self {
+            BoardFilter::Exclude(_) => "exclude",
+            BoardFilter::Include(_) => "include",
+        }
+    }
+}
+
+fn board_list(list: &str) -> Vec<String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .map(sanitize_board_name)
+        .filter(|board| !board.is_empty())
+        .collect()
+}
+
+// An original post together with its board and reply count
+struct Thread {
+    board: String,
+    post: Post,
+    replies: i64,
+}
+
+impl FromRow for Thread {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        // The last column is the bump time the query sorts by
+        let (board, id, post_id, poster_id, name, tripcode, title, message, file_path, created_at, replies, _bumped): (_, _, _, _, _, _, _, _, _, _, _, i64) =
+            mysql_async::from_row_opt(row)?;
+        Ok(Thread {
+            board,
+            post: Post { id, post_id, poster_id, name, tripcode, title, message, file_path, created_at },
+            replies,
+        })
+    }
+}
+
+// The most recently bumped threads across `boards`
+async fn load_threads(conn: &mut Conn, boards: &[String], limit: usize, offset: usize) -> Result<Vec<Thread>, mysql_async::Error> {
+    let selects: Vec<String> = boards
+        .iter()
+        .map(|board| format!(
+            "(SELECT '{board}', {columns}, \
+             (SELECT COUNT(*) FROM {board}_files replies WHERE replies.parent_id = threads.id AND replies.pending = FALSE), \
+             UNIX_TIMESTAMP(last_reply_at) AS bumped \
+             FROM {board}_files threads WHERE parent_id = 0 AND pending = FALSE ORDER BY last_reply_at DESC LIMIT {depth})",
+            board = board,
+            columns = POST_COLUMNS,
+            depth = offset + limit
+        ))
+        .collect();
+    if selects.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let query = format!("{} ORDER BY bumped DESC LIMIT {} OFFSET {}", selects.join(" UNION ALL "), limit, offset);
+    info!("Executing query: {}", query);
+    conn.query(&query).await
+}
+
+// Public boards the visitor has not filtered out
+async fn shown_boards(conn: &mut Conn, filter: &BoardFilter) -> Result<Vec<String>, mysql_async::Error> {
+    let query = "SELECT name FROM boards WHERE visibility = 'public' ORDER BY name";
+    info!("Executing query: {}", query);
+    let boards: Vec<String> = conn.query(query).await?;
+    Ok(boards
+        .into_iter()
+        .map(|board| sanitize_board_name(&board))
+        .filter(|board| filter.shows(board))
+        .collect())
+}
+
+// At most `length` characters, cut on a character boundary
+fn preview(message: &str, length: usize) -> (&str, bool) {
+    match message.char_indices().nth(length) {
+        Some((cut, _)) => (&message[..cut], true),
+        None => (message, false),
+    }
+}
+
+fn render_thread(thread: &Thread, timezone: Tz) -> String {
+    let post = &thread.post;
+    let (message, cut) = preview(&post.message, PREVIEW_LENGTH);
+    let mut html = String::from("<div class=\"post\">");
+    html.push_str(&format!(
+        "<div class=\"post-id\"><a class=\"overboard-board\" href=\"/{board}\">/{board}/</a> <a href=\"/{board}/post/{id}\">No.{id}</a> {poster}</div>",
+        board = thread.board,
+        id = post.id,
+        poster = render_poster(post)
+    ));
+    html.push_str(&format_post_time(post.created_at, timezone));
+    html.push_str(&format!("<div class=\"post-title title-green\">{}</div>", escape_html(&post.title)));
+    if let Some(file_path) = &post.file_path {
+        html.push_str(&media_html(file_path));
+    }
+    html.push_str(&format!("<div class=\"post-message\">{}{}</div>", escape_html(message), if cut { "..." } else { "" }));
+    html.push_str(&format!(
+        "<a class=\"reply-button\" href=\"/{}/post/{}\">Reply ({})</a>",
+        thread.board, post.id, thread.replies
+    ));
+    html.push_str("</div>");
+    html
+}
+
+fn render_catalog_entry(thread: &Thread) -> String {
+    let post = &thread.post;
+    let (message, cut) = preview(&post.message, CATALOG_PREVIEW_LENGTH);
+    let image = match &post.file_path {
+        Some(file_path) if media_info::mime_type(file_path).starts_with("image/") => {
+            format!(r#"<img src="{}">"#, escape_html(&media_url(file_path)))
+        }
+        Some(_) => "<div class=\"catalog-video\">Video</div>".to_string(),
+        None => String::new(),
+    };
+    format!(
+        "<a class=\"catalog-entry\" href=\"/{board}/post/{id}\">{image}<div class=\"catalog-meta\">/{board}/ R: {replies}</div><div class=\"post-title\">{title}</div><div class=\"catalog-message\">{message}{more}</div></a>",
+        board = thread.board,
+        id = post.id,
+        image = image,
+        replies = thread.replies,
+        title = escape_html(&post.title),
+        message = escape_html(message),
+        more = if cut { "..." } else { "" }
+    )
+}
+
+async fn render_page(req: &HttpRequest, pool: &Pool, query: &HashMap<String, String>, catalog: bool) -> Result<HttpResponse> {
+    let per_page = if catalog { CATALOG_PER_PAGE } else { THREADS_PER_PAGE };
+    let page: usize = query.get("page").and_then(|page| page.parse().ok()).unwrap_or(1).clamp(1, MAX_PAGES);
+    let filter = BoardFilter::from_request(req);
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let boards = shown_boards(&mut conn, &filter).await.map_err(ErrorInternalServerError)?;
+    // One more than a page tells whether there is a next one
+    let mut threads = load_threads(&mut conn, &boards, per_page + 1, (page - 1) * per_page)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let has_next = threads.len() > per_page && page < MAX_PAGES;
+    threads.truncate(per_page);
+
+    let timezone = visitor_timezone(req);
+    let mut threads_html = String::new();
+    for thread in &threads {
+        if catalog {
+            threads_html.push_str(&render_catalog_entry(thread));
+        } else {
+            threads_html.push_str(&render_thread(thread, timezone));
+        }
+    }
+    if threads_html.is_empty() {
+        threads_html.push_str("<p>No threads to show.</p>");
+    }
+
+    let path = if catalog { "/overboard/catalog" } else { "/overboard" };
+    let mut pagination_html = String::new();
+    if page > 1 {
+        pagination_html.push_str(&format!(r#"<a href="{}?page={}">Previous</a>"#, path, page - 1));
+    }
+    if has_next {
+        pagination_html.push_str(&format!(r#"<a href="{}?page={}">Next</a>"#, path, page + 1));
+    }
+
+    let mode_options: String = [("exclude", "All boards except"), ("include", "Only these boards")]
+        .iter()
+        .map(|(mode, label)| {
+            let selected = if *mode == filter.mode() { " selected" } else { "" };
+            format!("<option value=\"{}\"{}>{}</option>", mode, selected, label)
+        })
+        .collect();
+
+    let mut context = HashMap::new();
+    context.insert("TITLE", if catalog { "Overboard Catalog" } else { "Overboard" }.to_string());
+    context.insert(
+        "VIEW_LINK",
+        if catalog { r#"<a href="/overboard">Index</a>"# } else { r#"<a href="/overboard/catalog">Catalog</a>"# }.to_string(),
+    );
+    context.insert("THREADS_CLASS", if catalog { "catalog" } else { "posts" }.to_string());
+    context.insert("THREADS", threads_html);
+    context.insert("PAGINATION", pagination_html);
+    context.insert("MODE_OPTIONS", mode_options);
+    context.insert("FILTER_BOARDS", escape_html(&filter.boards().join(", ")));
+    context.insert("TIMEZONE", timezone.name().to_string());
+    let body = render_template("templates/overboard.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn overboard(req: HttpRequest, pool: web::Data<Pool>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    render_page(&req, &pool, &query, false).await
+}
+
+pub async fn catalog(req: HttpRequest, pool: web::Data<Pool>, query: web::Query<HashMap<String, String>>) -> Result<HttpResponse> {
+    render_page(&req, &pool, &query, true).await
+}
+
+// Remember the visitor's board filter; an empty exclude list clears it
+pub async fn set_filter(req: HttpRequest, form: web::Form<HashMap<String, String>>) -> Result<HttpResponse> {
+    let back = back_url(&req, "/overboard");
+    let mode = if form.get("mode").map(String::as_str) == Some("include") { "include" } else { "exclude" };
+    let boards = board_list(form.get("boards").map(String::as_str).unwrap_or(""));
+
+    let mut response = HttpResponse::SeeOther();
+    response.append_header(("Location", back));
+    if mode == "exclude" && boards.is_empty() {
+        let mut cookie = Cookie::new(FILTER_COOKIE, "");
+        cookie.set_path("/");
+        cookie.make_removal();
+        return Ok(response.cookie(cookie).finish());
+    }
+
+    let cookie = Cookie::build(FILTER_COOKIE, format!("{}:{}", mode, boards.join(",")))
+        .path("/")
+        .max_age(CookieDuration::days(365))
+        .finish();
+    Ok(response.cookie(cookie).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_lists_are_cleaned_up() {
+        assert_eq!(board_list("a, b c,,../d"), vec!["a", "b", "c", "d"]);
+        assert!(board_list(" , ").is_empty());
+    }
+
+    #[test]
+    fn filters_exclude_or_include_boards() {
+        let exclude = BoardFilter::Exclude(board_list("b"));
+        assert!(!exclude.shows("b"));
+        assert!(exclude.shows("g"));
+        let include = BoardFilter::Include(board_list("b"));
+        assert!(include.shows("b"));
+        assert!(!include.shows("g"));
+    }
+
+    #[test]
+    fn previews_cut_on_character_boundaries() {
+        assert_eq!(preview("héllo", 2), ("hé", true));
+        assert_eq!(preview("hi", 2), ("hi", false));
+    }
+}
@@ -0,0 +1,100 @@
+use actix_web::cookie::{time::Duration, Cookie, CookieJar, Key};
+use actix_web::HttpRequest;
+use std::collections::HashSet;
+
+const COOKIE_NAME: &str = "own_posts";
+// Oldest entries are dropped beyond this so the cookie stays well under the browser size limit
+const MAX_REMEMBERED: usize = 150;
+
+// Posts made from this browser as `board:id` entries, read from a cookie signed with the server key
+fn remembered(req: &HttpRequest, key: &Key) -> Vec<String> {
+    let Some(cookie) = req.cookie(COOKIE_NAME) else {
+        return Vec::new();
+    };
+
+    let mut jar = CookieJar::new();
+    jar.add_original(cookie);
+    match jar.signed(key).get(COOKIE_NAME) {
+        Some(verified) => verified.value()
+            .split(',')
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// IDs of the visitor's own posts on one board
+pub fn own_post_ids(req: &HttpRequest, key: &Key, board_name: &str) -> HashSet<i32> {
+    remembered(req, key)
+        .iter()
+        .filter_map(|entry| entry.split_once(':'))
+        .filter(|(board, _)| *board == board_name)
+        .filter_map(|(_, id)| id.parse().ok())
+        .collect()
+}
+
+// The updated cookie after the visitor made a new post
+pub fn remember(req: &HttpRequest, key: &Key, board_name: &str, id: u64) -> Cookie<'static> {
+    let mut entries = remembered(req, key);
+    entries.push(format!("{}:{}", board_name, id));
+    if entries.len() > MAX_REMEMBERED {
+        entries.drain(..entries.len() - MAX_REMEMBERED);
+    }
+
+    let mut jar = CookieJar::new();
+    jar.signed_mut(key).add(
+        Cookie::build(COOKIE_NAME, entries.join(","))
+            .path("/")
+            .max_age(Duration::days(365))
+            .http_only(true)
+            .finish(),
+    );
+    jar.get(COOKIE_NAME).cloned().expect("signed cookie was just added")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn key() -> Key {
+        Key::from(&[7u8; 64])
+    }
+
+    #[test]
+    fn remembered_posts_are_read_back_per_board() {
+        let cookie = remember(&TestRequest::default().to_http_request(), &key(), "b", 5);
+        let req = TestRequest::default().cookie(cookie).to_http_request();
+        let cookie = remember(&req, &key(), "g", 9);
+        let req = TestRequest::default().cookie(cookie).to_http_request();
+
+        assert_eq!(own_post_ids(&req, &key(), "b"), HashSet::from([5]));
+        assert_eq!(own_post_ids(&req, &key(), "g"), HashSet::from([9]));
+        assert!(own_post_ids(&req, &key(), "v").is_empty());
+    }
+
+    #[test]
+    fn unsigned_or_forged_cookies_are_ignored() {
+        let forged = Cookie::new(COOKIE_NAME, "b:1,b:2");
+        let req = TestRequest::default().cookie(forged).to_http_request();
+        assert!(own_post_ids(&req, &key(), "b").is_empty());
+
+        let cookie = remember(&TestRequest::default().to_http_request(), &key(), "b", 5);
+        let req = TestRequest::default().cookie(cookie).to_http_request();
+        assert!(own_post_ids(&req, &Key::from(&[8u8; 64]), "b").is_empty());
+    }
+
+    #[test]
+    fn oldest_posts_are_dropped_past_the_limit() {
+        let mut req = TestRequest::default().to_http_request();
+        for id in 0..(MAX_REMEMBERED as u64 + 10) {
+            let cookie = remember(&req, &key(), "b", id);
+            req = TestRequest::default().cookie(cookie).to_http_request();
+        }
+        let ids = own_post_ids(&req, &key(), "b");
+        assert_eq!(ids.len(), MAX_REMEMBERED);
+        assert!(!ids.contains(&9));
+        assert!(ids.contains(&10));
+    }
+}
@@ -0,0 +1,345 @@
+use crate::access::{self, Visibility};
+use crate::audit::{self, Action};
+use crate::boards::{self, SettingKind, EDITABLE_SETTINGS};
+use crate::config::Config;
+use crate::moderation::{self, Role, Staff};
+use crate::{escape_html, passwords, render_template, sanitize_board_name, schema};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpResponse, Result};
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Pool, Row, Value};
+use std::collections::HashMap;
+
+// Boards the account runs, with the form for a new one
+async fn boards_page(staff: &Staff, pool: &Pool, config: &Config, message: &str) -> Result<HttpResponse> {
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = "SELECT boards.name, boards.title, staff.username FROM boards \
+        LEFT JOIN staff ON staff.id = boards.owner_id ORDER BY boards.name";
+    info!("Executing query: {}", query);
+    let rows: Vec<(String, String, Option<String>)> = conn.query(query).await.map_err(ErrorInternalServerError)?;
+
+    let mut boards_html = String::new();
+    for (name, title, owner) in rows.into_iter().filter(|(name, ..)| staff.owns(name)) {
+        boards_html.push_str(&format!(
+            r#"<tr><td><a href="/{name}">/{name}/</a></td><td>{title}</td><td>{owner}</td><td><a href="/mod/boards/{name}">Manage</a></td></tr>"#,
+            name = name,
+            title = escape_html(&title),
+            owner = owner.map(|owner| escape_html(&owner)).unwrap_or_else(|| "-".to_string())
+        ));
+    }
+    if boards_html.is_empty() {
+        boards_html.push_str(r#"<tr><td colspan="4">You don't run any boards yet.</td></tr>"#);
+    }
+
+    let create_form = if config.board_creation || staff.is_global() {
+        r#"<form action="/mod/boards" method="post"><input type="text" name="name" maxlength="32" placeholder="Name, e.g. chess" required><br><input type="text" name="title" maxlength="100" placeholder="Title"><br><button type="submit">Create Board</button></form>"#
+    } else {
+        "<p>Creating boards is switched off.</p>"
+    };
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", moderation::nav(staff));
+    context.insert("MESSAGE", escape_html(message));
+    context.insert("BOARDS", boards_html);
+    context.insert("CREATE_FORM", create_form.to_string());
+    let body = render_template("templates/mod_boards.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn show_boards(staff: Staff, pool: web::Data<Pool>, config: web::Data<Config>) -> Result<HttpResponse> {
+    boards_page(&staff, &pool, &config, "").await
+}
+
+// Board owners create boards they then own; global staff create unowned ones and skip the switch and the limit
+pub async fn create_board(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let name = form.get("name").map(|name| name.trim()).unwrap_or("");
+    let title = form.get("title").map(|title| title.trim()).unwrap_or("");
+    if !staff.is_global() && !config.board_creation {
+        return boards_page(&staff, &pool, &config, "Creating boards is switched off.").await;
+    }
+    if let Some(problem) = boards::invalid_name(name) {
+        return boards_page(&staff, &pool, &config, problem).await;
+    }
+    let title = match boards::parse_setting("title", title) {
+        Ok(title) => title,
+        Err(problem) => return boards_page(&staff, &pool, &config, &problem).await,
+    };
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let exists_query = "SELECT COUNT(*) FROM boards WHERE name = ?";
+    info!("Executing query: {}", exists_query);
+    let exists: Option<i64> = conn.exec_first(exists_query, (name,)).await.map_err(ErrorInternalServerError)?;
+    if exists.unwrap_or(0) > 0 {
+        return boards_page(&staff, &pool, &config, "A board with that name already exists.").await;
+    }
+
+    if !staff.is_global() {
+        let recent_query = "SELECT COUNT(*) FROM boards WHERE owner_id = ? AND created_at > CURRENT_TIMESTAMP - INTERVAL 1 DAY";
+        info!("Executing query: {}", recent_query);
+        let recent: Option<i64> = conn.exec_first(recent_query, (staff.id,)).await.map_err(ErrorInternalServerError)?;
+        if recent.unwrap_or(0) >= config.boards_per_day {
+            let message = format!("You can create {} board(s) a day, try again tomorrow.", config.boards_per_day);
+            return boards_page(&staff, &pool, &config, &message).await;
+        }
+    }
+
+    schema::create_board(&mut conn, name).await.map_err(ErrorInternalServerError)?;
+    let owner_id = if staff.is_global() { None } else { Some(staff.id) };
+    let owner_query = "UPDATE boards SET owner_id = ?, title = ? WHERE name = ?";
+    info!("Executing query: {}", owner_query);
+    conn.exec_drop(owner_query, (owner_id, title, name)).await.map_err(ErrorInternalServerError)?;
+    audit::record(&mut conn, &staff, Action::CreateBoard, Some(name), None, None, None)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/mod/boards/{}", name))).finish())
+}
+
+// A column value as the text shown in the settings form
+fn setting_text(value: Option<Value>) -> String {
+    match value {
+        Some(Value::Bytes(bytes)) => String::from_utf8_lossy(&bytes).into_owned(),
+        Some(Value::Int(number)) => number.to_string(),
+        Some(Value::UInt(number)) => number.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn setting_input(key: &str, kind: &SettingKind, current: &str) -> String {
+    match kind {
+        SettingKind::Flag => {
+            let checked = if current == "1" { " checked" } else { "" };
+            format!(r#"<input type="checkbox" name="{}" value="true"{}>"#, key, checked)
+        }
+        SettingKind::Number(min, max) => format!(
+            r#"<input type="number" name="{}" min="{}" max="{}" value="{}">"#,
+            key, min, max, escape_html(current)
+        ),
+        SettingKind::Choice(options) => {
+            let options: String = options
+                .iter()
+                .map(|option| {
+                    let selected = if *option == current { " selected" } else { "" };
+                    format!("<option value=\"{}\"{}>{}</option>", option, selected, option)
+                })
+                .collect();
+            format!(r#"<select name="{}">{}</select>"#, key, options)
+        }
+        SettingKind::Subset(options) => format!(
+            r#"<input type="text" name="{}" value="{}" placeholder="{}">"#,
+            key, escape_html(current), options.join(", ")
+        ),
+        SettingKind::Text(max) if *max > 255 => format!(
+            r#"<textarea name="{}" maxlength="{}">{}</textarea>"#,
+            key, max, escape_html(current)
+        ),
+        SettingKind::Text(max) => format!(
+            r#"<input type="text" name="{}" maxlength="{}" value="{}">"#,
+            key, max, escape_html(current)
+        ),
+    }
+}
+
+// Settings, owner and volunteers of one board
+async fn board_page(staff: &Staff, pool: &Pool, board_name: &str, message: &str) -> Result<HttpResponse> {
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let columns: Vec<String> = EDITABLE_SETTINGS.iter().map(|(key, _)| format!("boards.{}", key)).collect();
+    let query = format!(
+        "SELECT {}, staff.username FROM boards LEFT JOIN staff ON staff.id = boards.owner_id WHERE boards.name = ?",
+        columns.join(", ")
+    );
+    info!("Executing query: {}", query);
+    let row: Option<Row> = conn.exec_first(&query, (board_name,)).await.map_err(ErrorInternalServerError)?;
+    let Some(mut row) = row else {
+        return Ok(HttpResponse::NotFound().body("No such board."));
+    };
+
+    let mut settings_html = String::new();
+    for (index, (key, kind)) in EDITABLE_SETTINGS.iter().enumerate() {
+        let current = setting_text(row.take(index));
+        settings_html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td></tr>",
+            key,
+            setting_input(key, kind, &current)
+        ));
+    }
+    settings_html.push_str(r#"<tr><td>access_password</td><td><input type="password" name="access_password" placeholder="Unchanged when empty"></td></tr>"#);
+    if staff.is_global() {
+        let owner: Option<String> = row.take(EDITABLE_SETTINGS.len()).flatten();
+        settings_html.push_str(&format!(
+            r#"<tr><td>owner</td><td><input type="text" name="owner" maxlength="32" value="{}" placeholder="No owner"></td></tr>"#,
+            escape_html(&owner.unwrap_or_default())
+        ));
+    }
+
+    let volunteers_query = "SELECT staff.id, staff.username FROM staff_boards \
+        JOIN staff ON staff.id = staff_boards.staff_id WHERE staff_boards.board = ? ORDER BY staff.username";
+    info!("Executing query: {}", volunteers_query);
+    let volunteers: Vec<(i32, String)> = conn.exec(volunteers_query, (board_name,)).await.map_err(ErrorInternalServerError)?;
+    let mut volunteers_html = String::new();
+    for (id, username) in volunteers {
+        volunteers_html.push_str(&format!(
+            r#"<tr><td>{}</td><td><form class="delete-form" action="/mod/boards/{}/volunteers/{}/remove" method="post"><button type="submit">Remove</button></form></td></tr>"#,
+            escape_html(&username),
+            board_name,
+            id
+        ));
+    }
+    if volunteers_html.is_empty() {
+        volunteers_html.push_str(r#"<tr><td colspan="2">No volunteers yet.</td></tr>"#);
+    }
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", moderation::nav(staff));
+    context.insert("MESSAGE", escape_html(message));
+    context.insert("BOARD_NAME", board_name.to_string());
+    context.insert("SETTINGS", settings_html);
+    context.insert("VOLUNTEERS", volunteers_html);
+    let body = render_template("templates/mod_board.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn show_board(staff: Staff, pool: web::Data<Pool>, path: web::Path<String>) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    board_page(&staff, &pool, &board_name, "").await
+}
+
+// Every setting is sent with the form; unchecked boxes are missing and mean false
+pub async fn save_board(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<String>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let mut settings = Vec::new();
+    for (key, kind) in EDITABLE_SETTINGS {
+        let value = match (form.get(*key), kind) {
+            (Some(value), _) => value.as_str(),
+            (None, SettingKind::Flag) => "false",
+            (None, _) => continue,
+        };
+        match boards::parse_setting(key, value) {
+            Ok(value) => settings.push((*key, value)),
+            Err(problem) => return board_page(&staff, &pool, &board_name, &problem).await,
+        }
+    }
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let owner_id = match form.get("owner").map(|owner| owner.trim()) {
+        Some(owner) if staff.is_global() && !owner.is_empty() => {
+            let owner_query = "SELECT id FROM staff WHERE username = ? AND role = ?";
+            info!("Executing query: {}", owner_query);
+            let owner_id: Option<i32> = conn.exec_first(owner_query, (owner, Role::Owner.key())).await.map_err(ErrorInternalServerError)?;
+            match owner_id {
+                Some(owner_id) => Some(Some(owner_id)),
+                None => return board_page(&staff, &pool, &board_name, "The owner has to be a board owner account.").await,
+            }
+        }
+        Some(_) if staff.is_global() => Some(None),
+        _ => None,
+    };
+
+    let old_settings = boards::load_settings(&mut conn, &board_name).await.map_err(ErrorInternalServerError)?;
+    for (key, value) in settings {
+        boards::save_setting(&mut conn, &board_name, key, value).await.map_err(ErrorInternalServerError)?;
+    }
+    if let Some(owner_id) = owner_id {
+        let owner_query = "UPDATE boards SET owner_id = ? WHERE name = ?";
+        info!("Executing query: {}", owner_query);
+        conn.exec_drop(owner_query, (owner_id, &board_name)).await.map_err(ErrorInternalServerError)?;
+    }
+    if let Some(password) = form.get("access_password").filter(|password| !password.is_empty()) {
+        access::set_password(&mut conn, &board_name, Some(password)).await.map_err(ErrorInternalServerError)?;
+    }
+    let visibility = Visibility::from_setting(form.get("visibility").map(String::as_str).unwrap_or(""));
+    if visibility != old_settings.visibility {
+        access::relocate_media(&mut conn, &board_name, visibility).await.map_err(ErrorInternalServerError)?;
+    }
+
+    audit::record(&mut conn, &staff, Action::EditBoard, Some(&board_name), None, None, None)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    board_page(&staff, &pool, &board_name, "Settings saved.").await
+}
+
+// A one-use invite for a private board, valid for a week
+pub async fn create_invite(staff: Staff, pool: web::Data<Pool>, path: web::Path<String>) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let token = access::create_invite(&mut conn, &board_name, Some(1), Some(7)).await.map_err(ErrorInternalServerError)?;
+    let message = format!("Invite link, good for one visitor within a week: /{}/login?invite={}", board_name, token);
+    board_page(&staff, &pool, &board_name, &message).await
+}
+
+// Volunteers are janitor accounts assigned to the board. Owners can name an existing janitor
+// or make a new janitor account, which then only has access to this board.
+pub async fn add_volunteer(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<String>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let username = form.get("username").map(|username| username.trim()).unwrap_or("");
+    let password = form.get("password").map(String::as_str).unwrap_or("");
+    if username.is_empty() || username.chars().count() > 32 {
+        return board_page(&staff, &pool, &board_name, "Usernames must be between 1 and 32 characters.").await;
+    }
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let account_query = "SELECT id, role FROM staff WHERE username = ?";
+    info!("Executing query: {}", account_query);
+    let account: Option<(i32, String)> = conn.exec_first(account_query, (username,)).await.map_err(ErrorInternalServerError)?;
+    let volunteer_id = match account {
+        Some((id, role)) if role == Role::Janitor.key() => id,
+        Some(_) => return board_page(&staff, &pool, &board_name, "Only janitor accounts can be volunteers.").await,
+        None => {
+            if password.chars().count() < 8 {
+                return board_page(&staff, &pool, &board_name, "New accounts need a password of at least 8 characters.").await;
+            }
+            let password_hash = passwords::hash_password(password).map_err(ErrorInternalServerError)?;
+            let insert_query = "INSERT INTO staff (username, password_hash, role) VALUES (?, ?, ?)";
+            info!("Executing query: {}", insert_query);
+            conn.exec_drop(insert_query, (username, password_hash, Role::Janitor.key()))
+                .await
+                .map_err(ErrorInternalServerError)?;
+            conn.last_insert_id().unwrap_or(0) as i32
+        }
+    };
+
+    let assign_query = "INSERT IGNORE INTO staff_boards (staff_id, board) VALUES (?, ?)";
+    info!("Executing query: {}", assign_query);
+    conn.exec_drop(assign_query, (volunteer_id, &board_name)).await.map_err(ErrorInternalServerError)?;
+    audit::record(&mut conn, &staff, Action::AddVolunteer, Some(&board_name), None, None, Some(username.to_string()))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    board_page(&staff, &pool, &board_name, "Volunteer added.").await
+}
+
+// Takes the board away from the volunteer; the account itself stays
+pub async fn remove_volunteer(staff: Staff, pool: web::Data<Pool>, path: web::Path<(String, i32)>) -> Result<HttpResponse> {
+    let (board_name, id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let name_query = "SELECT username FROM staff WHERE id = ?";
+    info!("Executing query: {}", name_query);
+    let username: Option<String> = conn.exec_first(name_query, (id,)).await.map_err(ErrorInternalServerError)?;
+
+    let query = "DELETE FROM staff_boards WHERE staff_id = ? AND board = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (id, &board_name)).await.map_err(ErrorInternalServerError)?;
+    if conn.affected_rows() > 0 {
+        audit::record(&mut conn, &staff, Action::RemoveVolunteer, Some(&board_name), None, None, username)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    Ok(HttpResponse::SeeOther().append_header(("Location", format!("/mod/boards/{}", board_name))).finish())
+}
+
@@ -0,0 +1,324 @@
+use crate::config::Config;
+use crate::moderation;
+use crate::ratelimit::{KeyLimiter, PostLimiter};
+use crate::{
+    admit_file, admit_poster, captcha, create_upload, pow, publish, read_form, sanitize_board_name, write_upload, PostError, Poster,
+    Published, Submission, Upload,
+};
+use actix_multipart::Multipart;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use futures_util::stream::StreamExt as _;
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, Pool};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+// Largest JSON body accepted, enough for a file of about 15 MB once base64 encoded
+const MAX_JSON_BYTES: usize = 20 * 1024 * 1024;
+pub const DEFAULT_POSTS_PER_MINUTE: u32 = 10;
+
+// A key that lets a bot post without solving CAPTCHAs, limited by its own rate instead of its address
+struct ApiKey {
+    id: i32,
+    posts_per_minute: u32,
+    // Keys limited to the boards in `api_key_boards`; the others may post on every board that isn't private
+    scoped: bool,
+    boards: Vec<String>,
+}
+
+impl ApiKey {
+    fn allows(&self, board_name: &str, private: bool) -> bool {
+        if self.scoped || private {
+            self.boards.iter().any(|board| board == board_name)
+        } else {
+            true
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+async fn find_key(conn: &mut Conn, token: &str) -> Result<Option<ApiKey>, mysql_async::Error> {
+    let query = "SELECT id, posts_per_minute, scoped FROM api_keys WHERE token_hash = ? AND revoked = FALSE";
+    info!("Executing query: {}", query);
+    let key: Option<(i32, u32, bool)> = conn.exec_first(query, (hash_token(token),)).await?;
+    let Some((id, posts_per_minute, scoped)) = key else {
+        return Ok(None);
+    };
+
+    let boards_query = "SELECT board FROM api_key_boards WHERE key_id = ?";
+    info!("Executing query: {}", boards_query);
+    let boards: Vec<String> = conn.exec(boards_query, (id,)).await?;
+    let used_query = "UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP WHERE id = ?";
+    info!("Executing query: {}", used_query);
+    conn.exec_drop(used_query, (id,)).await?;
+    Ok(Some(ApiKey { id, posts_per_minute, scoped, boards }))
+}
+
+fn error(status: StatusCode, code: &str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "error": { "code": code, "message": message } }))
+}
+
+// The JSON answer for each reason a post can be refused
+fn refusal(err: PostError) -> Result<HttpResponse> {
+    Ok(match err {
+        PostError::Banned => error(StatusCode::FORBIDDEN, "banned", "You are banned from posting here, see /banned for details."),
+        PostError::TooFast(wait) => HttpResponse::TooManyRequests()
+            .append_header(("Retry-After", wait.to_string()))
+            .json(json!({ "error": { "code": "too_fast", "message": "You are posting too fast.", "retry_after": wait } })),
+        PostError::Muted { seconds, unoriginal } => {
+            let (code, message) = if unoriginal {
+                ("unoriginal", "This board only accepts original content and that has been posted before.")
+            } else {
+                ("muted", "You posted unoriginal content on this board.")
+            };
+            HttpResponse::Forbidden().json(json!({ "error": { "code": code, "message": message, "retry_after": seconds } }))
+        }
+        PostError::ChallengeFailed => error(
+            StatusCode::FORBIDDEN,
+            "challenge_failed",
+            "This board needs a CAPTCHA or proof-of-work answer, or an API key.",
+        ),
+        PostError::Invalid { field, message } => HttpResponse::UnprocessableEntity()
+            .json(json!({ "error": { "code": "invalid", "field": field, "message": message } })),
+        PostError::Malformed { field, message } => HttpResponse::BadRequest()
+            .json(json!({ "error": { "code": "malformed", "field": field, "message": message } })),
+        PostError::Filtered(reason) => error(StatusCode::UNPROCESSABLE_ENTITY, "filtered", &reason),
+        PostError::Internal(err) => return Err(err),
+    })
+}
+
+fn malformed(field: &'static str, message: &str) -> PostError {
+    PostError::Malformed { field, message: message.to_string() }
+}
+
+// A missing or null `thread` starts a new one; anything else has to be a thread number, never silently 0
+fn thread_number(body: &Value) -> Result<i32, PostError> {
+    match body.get("thread").filter(|thread| !thread.is_null()) {
+        Some(thread) => thread
+            .as_i64()
+            .and_then(|thread| i32::try_from(thread).ok())
+            .filter(|thread| *thread >= 0)
+            .ok_or_else(|| malformed("thread", "thread must be the number of an existing thread, or 0 for a new one.")),
+        None => Ok(0),
+    }
+}
+
+fn text_field(body: &Value, name: &str) -> String {
+    body.get(name).and_then(Value::as_str).unwrap_or("").to_string()
+}
+
+// A post sent as JSON, with its file as `{"name": ..., "data": <base64>}`
+async fn read_json(
+    payload: &mut web::Payload,
+    poster: &Poster,
+    board_name: &str,
+    captchas: &captcha::CaptchaStore,
+    proofs: &pow::PowStore,
+    submission: &mut Submission,
+) -> Result<(), PostError> {
+    let mut bytes = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(ErrorInternalServerError)?;
+        if bytes.len() + chunk.len() > MAX_JSON_BYTES {
+            return Err(PostError::invalid("file", "The request is too large."));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    let body: Value = serde_json::from_slice(&bytes).map_err(|_| malformed("body", "The body is not valid JSON."))?;
+
+    submission.raw_name = text_field(&body, "name");
+    submission.password = text_field(&body, "password");
+    submission.title = text_field(&body, "title");
+    submission.message = text_field(&body, "message");
+    submission.parent_id = thread_number(&body)?;
+    submission.answers.captcha_token = text_field(&body, "captcha_token");
+    submission.answers.captcha = text_field(&body, "captcha");
+    submission.answers.pow_token = text_field(&body, "pow_token");
+    submission.answers.pow_solution = text_field(&body, "pow_solution");
+
+    let Some(file) = body.get("file").filter(|file| !file.is_null()) else {
+        return Ok(());
+    };
+    let filename = text_field(file, "name");
+    if filename.is_empty() {
+        return Err(PostError::invalid("file", "Files need a name."));
+    }
+    let data = STANDARD
+        .decode(text_field(file, "data"))
+        .map_err(|_| PostError::invalid("file", "The file data is not valid base64."))?;
+
+    admit_file(poster, submission, &filename, board_name, captchas, proofs)?;
+    let (file_path, f) = create_upload(board_name, &poster.settings, &filename).await?;
+    let upload = Upload::new(file_path);
+    submission.image_hash = Some(Sha256::digest(&data).to_vec());
+    write_upload(&f, web::Bytes::from(data)).await?;
+    submission.upload = Some(upload);
+    submission.original_filename = Some(filename);
+    Ok(())
+}
+
+// POST /api/v1/{board}/posts: the board form's fields as multipart, or the same as JSON with `thread` for the
+// thread to reply to. Answers 201 with the new post's number and thread, or an error object.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_post(
+    req: HttpRequest,
+    mut payload: web::Payload,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+    limiter: web::Data<PostLimiter>,
+    key_limiter: web::Data<KeyLimiter>,
+    captchas: web::Data<captcha::CaptchaStore>,
+    proofs: web::Data<pow::PowStore>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let board_name = sanitize_board_name(&path.into_inner());
+    let token = req.headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = "SELECT visibility FROM boards WHERE name = ?";
+    info!("Executing query: {}", query);
+    let visibility: Option<String> = conn.exec_first(query, (&board_name,)).await.map_err(ErrorInternalServerError)?;
+    let Some(visibility) = visibility else {
+        return Ok(error(StatusCode::NOT_FOUND, "not_found", "There is no such board."));
+    };
+    let private = visibility == "private";
+
+    let key = match token {
+        Some(token) => match find_key(&mut conn, &token).await.map_err(ErrorInternalServerError)? {
+            Some(key) => Some(key),
+            None => return Ok(error(StatusCode::UNAUTHORIZED, "invalid_key", "The API key is unknown or revoked.")),
+        },
+        None => None,
+    };
+    drop(conn);
+    match &key {
+        Some(key) if !key.allows(&board_name, private) => {
+            return Ok(error(StatusCode::FORBIDDEN, "out_of_scope", "The API key may not post on this board."));
+        }
+        Some(key) => {
+            if let Err(wait) = key_limiter.take(key.id, key.posts_per_minute) {
+                return refusal(PostError::TooFast(wait));
+            }
+        }
+        None if private => return Ok(error(StatusCode::FORBIDDEN, "private", "Private boards need an API key for this board.")),
+        None => {}
+    }
+
+    let is_multipart = req.headers()
+        .get("Content-Type")
+        .and_then(|header| header.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("multipart/form-data"));
+    let result = async {
+        let ip_limiter = if key.is_some() { None } else { Some(limiter.get_ref()) };
+        let poster = admit_poster(&req, &pool, &config, ip_limiter, &board_name).await?;
+        let mut submission = Submission { challenges_passed: key.is_some(), ..Submission::default() };
+        if is_multipart {
+            let mut form = Multipart::new(req.headers(), payload);
+            read_form(&mut form, &poster, &board_name, &captchas, &proofs, &mut submission).await?;
+        } else {
+            read_json(&mut payload, &poster, &board_name, &captchas, &proofs, &mut submission).await?;
+        }
+        publish(&pool, &config, &captchas, &proofs, poster, &board_name, submission).await
+    }
+    .await;
+
+    let Published { id, parent_id, awaiting_approval } = match result {
+        Ok(published) => published,
+        Err(err) => return refusal(err),
+    };
+    let id = id.unwrap_or(0);
+    Ok(HttpResponse::Created().json(json!({
+        "board": board_name,
+        "no": id,
+        "thread": if parent_id == 0 { id } else { parent_id as u64 },
+        "pending": awaiting_approval,
+    })))
+}
+
+// Store a new key and return its token, which is only ever shown this once
+pub async fn create_key(conn: &mut Conn, label: &str, boards: &[String], posts_per_minute: u32) -> Result<String, mysql_async::Error> {
+    let token = moderation::random_token(48);
+    let query = "INSERT INTO api_keys (label, token_hash, posts_per_minute, scoped) VALUES (?, ?, ?, ?)";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (label, hash_token(&token), posts_per_minute, !boards.is_empty())).await?;
+    let key_id = conn.last_insert_id().unwrap_or(0);
+
+    let board_query = "INSERT IGNORE INTO api_key_boards (key_id, board) VALUES (?, ?)";
+    for board in boards {
+        info!("Executing query: {}", board_query);
+        conn.exec_drop(board_query, (key_id, board)).await?;
+    }
+    Ok(token)
+}
+
+// Every key as (id, label, posts per minute, boards or "all", revoked, last used)
+pub async fn list_keys(conn: &mut Conn) -> Result<Vec<(i32, String, u32, String, bool, Option<String>)>, mysql_async::Error> {
+    let query = "SELECT api_keys.id, api_keys.label, api_keys.posts_per_minute, \
+        IF(api_keys.scoped, IFNULL(GROUP_CONCAT(api_key_boards.board ORDER BY api_key_boards.board), ''), 'all'), \
+        api_keys.revoked, DATE_FORMAT(api_keys.last_used_at, '%Y-%m-%d %H:%i') \
+        FROM api_keys LEFT JOIN api_key_boards ON api_key_boards.key_id = api_keys.id \
+        GROUP BY api_keys.id ORDER BY api_keys.id";
+    info!("Executing query: {}", query);
+    conn.query(query).await
+}
+
+// Returns whether there was such a key
+pub async fn revoke_key(conn: &mut Conn, key_id: i32) -> Result<bool, mysql_async::Error> {
+    let query = "UPDATE api_keys SET revoked = TRUE WHERE id = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (key_id,)).await?;
+    Ok(conn.affected_rows() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(scoped: bool, boards: &[&str]) -> ApiKey {
+        ApiKey { id: 1, posts_per_minute: 10, scoped, boards: boards.iter().map(|board| board.to_string()).collect() }
+    }
+
+    #[test]
+    fn unscoped_keys_post_on_every_board_that_isnt_private() {
+        assert!(key(false, &[]).allows("b", false));
+        assert!(!key(false, &[]).allows("secret", true));
+        assert!(key(false, &["secret"]).allows("secret", true));
+    }
+
+    #[test]
+    fn scoped_keys_only_post_on_their_boards() {
+        let scoped = key(true, &["b", "secret"]);
+        assert!(scoped.allows("b", false));
+        assert!(scoped.allows("secret", true));
+        assert!(!scoped.allows("g", false));
+        // A scoped key whose boards were all deleted stays limited to nothing
+        assert!(!key(true, &[]).allows("g", false));
+    }
+
+    #[test]
+    fn tokens_are_stored_as_sha256() {
+        assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn thread_numbers() {
+        assert!(matches!(thread_number(&json!({})), Ok(0)));
+        assert!(matches!(thread_number(&json!({ "thread": null })), Ok(0)));
+        assert!(matches!(thread_number(&json!({ "thread": 42 })), Ok(42)));
+        for thread in [json!(-1), json!(4294967338u64), json!(1.5), json!("42"), json!(true)] {
+            assert!(matches!(thread_number(&json!({ "thread": thread })), Err(PostError::Malformed { field: "thread", .. })));
+        }
+    }
+}
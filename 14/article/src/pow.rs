@@ -0,0 +1,229 @@
+use crate::ratelimit::PostLimiter;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(15 * 60);
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const MAX_DIFFICULTY: u32 = 32;
+// At most this many bits are added on top of a board's base difficulty while it is flooded
+const MAX_EXTRA_BITS: u32 = 8;
+// Challenges issued within one lifetime, beyond which new ones are refused until old ones expire
+const MAX_OPEN_CHALLENGES: usize = 10_000;
+// How many challenges one address may ask for at once, and how fast that allowance comes back
+const ISSUE_BURST: u32 = 30;
+const ISSUES_PER_MINUTE: u32 = 10;
+
+struct Challenge {
+    board: String,
+    difficulty: u32,
+    issued: Instant,
+}
+
+#[derive(Default)]
+struct Challenges {
+    by_token: HashMap<String, Challenge>,
+    // Tokens in the order they were issued, so expired ones are swept from the front
+    issued: VecDeque<(Instant, String)>,
+}
+
+// Open proof-of-work challenges and recent post times per board, kept in memory.
+// A challenge is removed when it is checked, so every solution can be used once.
+pub struct PowStore {
+    challenges: Mutex<Challenges>,
+    limiter: PostLimiter,
+    recent_posts: Mutex<HashMap<String, VecDeque<Instant>>>,
+    spike_threshold: usize,
+}
+
+impl PowStore {
+    // `spike_threshold` is the posts per minute above which a board's challenges get harder
+    pub fn new(spike_threshold: u32) -> PowStore {
+        PowStore {
+            challenges: Mutex::new(Challenges::default()),
+            limiter: PostLimiter::with_rate(ISSUE_BURST, ISSUES_PER_MINUTE),
+            recent_posts: Mutex::new(HashMap::new()),
+            spike_threshold: spike_threshold.max(1) as usize,
+        }
+    }
+
+    // One more bit for every doubling of the post rate above the spike threshold
+    fn difficulty(&self, board_name: &str, base: u32) -> u32 {
+        let mut recent_posts = self.recent_posts.lock().unwrap();
+        let posts = recent_posts.entry(board_name.to_string()).or_default();
+        while posts.front().is_some_and(|posted| posted.elapsed() > RATE_WINDOW) {
+            posts.pop_front();
+        }
+
+        let mut extra = 0;
+        let mut limit = self.spike_threshold;
+        while posts.len() >= limit && extra < MAX_EXTRA_BITS {
+            extra += 1;
+            limit *= 2;
+        }
+        (base + extra).min(MAX_DIFFICULTY)
+    }
+
+    pub fn record_post(&self, board_name: &str) {
+        self.recent_posts
+            .lock()
+            .unwrap()
+            .entry(board_name.to_string())
+            .or_default()
+            .push_back(Instant::now());
+    }
+
+    // A new challenge's token and difficulty, or None when the address asked for too many or the store is full
+    pub fn issue(&self, board_name: &str, base: u32, ip: Option<IpAddr>) -> Option<(String, u32)> {
+        if let Some(ip) = ip {
+            self.limiter.take(ip).ok()?;
+        }
+        let difficulty = self.difficulty(board_name, base);
+        let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+
+        let mut challenges = self.challenges.lock().unwrap();
+        while let Some((issued, _)) = challenges.issued.front() {
+            if issued.elapsed() < CHALLENGE_LIFETIME {
+                break;
+            }
+            if let Some((_, expired)) = challenges.issued.pop_front() {
+                challenges.by_token.remove(&expired);
+            }
+        }
+        if challenges.issued.len() >= MAX_OPEN_CHALLENGES {
+            return None;
+        }
+
+        let now = Instant::now();
+        challenges.by_token.insert(token.clone(), Challenge { board: board_name.to_string(), difficulty, issued: now });
+        challenges.issued.push_back((now, token.clone()));
+        Some((token, difficulty))
+    }
+
+    // A solution is a counter for which SHA-256("token:counter") starts with `difficulty` zero bits
+    pub fn verify(&self, board_name: &str, token: &str, solution: &str) -> bool {
+        let Some(challenge) = self.challenges.lock().unwrap().by_token.remove(token) else {
+            return false;
+        };
+        if challenge.board != board_name || challenge.issued.elapsed() >= CHALLENGE_LIFETIME {
+            return false;
+        }
+        if solution.is_empty() || solution.len() > 20 || !solution.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+        leading_zero_bits(&Sha256::digest(format!("{}:{}", token, solution))) >= challenge.difficulty
+    }
+}
+
+fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in digest {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
+// Hidden challenge fields for a post form, solved by static/pow.js when the form is submitted
+pub fn form_field(store: &PowStore, board_name: &str, base_difficulty: u32, ip: Option<IpAddr>) -> String {
+    if base_difficulty == 0 {
+        return String::new();
+    }
+    let Some((token, difficulty)) = store.issue(board_name, base_difficulty, ip) else {
+        return String::new();
+    };
+    format!(
+        r#"<div class="pow" data-difficulty="{difficulty}"><input type="hidden" name="pow_token" value="{token}"><input type="hidden" name="pow_solution" value=""><span class="pow-status"></span><noscript>Posting here needs JavaScript for the anti-spam check.</noscript></div><script src="/static/pow.js"></script>"#,
+        difficulty = difficulty,
+        token = token
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPIKE_THRESHOLD: u32 = 30;
+
+    fn solve(token: &str, difficulty: u32) -> String {
+        (0u64..)
+            .map(|counter| counter.to_string())
+            .find(|counter| leading_zero_bits(&Sha256::digest(format!("{}:{}", token, counter))) >= difficulty)
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xFF]), 0);
+        assert_eq!(leading_zero_bits(&[0x01]), 7);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x10]), 19);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn solution_is_accepted_once() {
+        let store = PowStore::new(SPIKE_THRESHOLD);
+        let (token, difficulty) = store.issue("b", 8, None).unwrap();
+        assert_eq!(difficulty, 8);
+        let solution = solve(&token, difficulty);
+        assert!(store.verify("b", &token, &solution));
+        assert!(!store.verify("b", &token, &solution));
+    }
+
+    #[test]
+    fn solution_is_bound_to_its_board() {
+        let store = PowStore::new(SPIKE_THRESHOLD);
+        let (token, difficulty) = store.issue("b", 8, None).unwrap();
+        assert!(!store.verify("g", &token, &solve(&token, difficulty)));
+    }
+
+    #[test]
+    fn malformed_solutions_are_refused() {
+        let store = PowStore::new(SPIKE_THRESHOLD);
+        for solution in ["", "12a", "-1", "123456789012345678901"] {
+            let (token, _) = store.issue("b", 0, None).unwrap();
+            assert!(!store.verify("b", &token, solution));
+        }
+    }
+
+    #[test]
+    fn difficulty_rises_while_a_board_is_flooded() {
+        let store = PowStore::new(SPIKE_THRESHOLD);
+        assert_eq!(store.difficulty("b", 10), 10);
+        for _ in 0..SPIKE_THRESHOLD {
+            store.record_post("b");
+        }
+        assert_eq!(store.difficulty("b", 10), 11);
+        assert_eq!(store.difficulty("g", 10), 10);
+        assert_eq!(store.difficulty("b", MAX_DIFFICULTY), MAX_DIFFICULTY);
+    }
+
+    #[test]
+    fn issuing_is_limited_per_address() {
+        let store = PowStore::new(SPIKE_THRESHOLD);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..ISSUE_BURST {
+            assert!(store.issue("b", 1, Some(ip)).is_some());
+        }
+        assert!(store.issue("b", 1, Some(ip)).is_none());
+        assert!(store.issue("b", 1, Some("192.0.2.2".parse().unwrap())).is_some());
+    }
+
+    #[test]
+    fn full_store_refuses_instead_of_evicting() {
+        let store = PowStore::new(SPIKE_THRESHOLD);
+        let (first, _) = store.issue("b", 1, None).unwrap();
+        for _ in 1..MAX_OPEN_CHALLENGES {
+            assert!(store.issue("b", 1, None).is_some());
+        }
+        assert!(store.issue("b", 1, None).is_none());
+        assert!(store.challenges.lock().unwrap().by_token.contains_key(&first));
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{back_url, message_page};
+use actix_web::{HttpRequest, HttpResponse};
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::Conn;
+use sha2::{Digest, Sha256};
+
+// Each offense doubles the mute, starting at two seconds and never longer than a day
+const MAX_MUTE_SECONDS: i64 = 24 * 60 * 60;
+// One offense is forgiven for every six hours without a new one
+const OFFENSE_DECAY_SECONDS: i64 = 6 * 60 * 60;
+
+const KIND_MESSAGE: &str = "m";
+const KIND_IMAGE: &str = "i";
+
+// Case, punctuation and whitespace don't make a message original
+fn normalize(message: &str) -> String {
+    message
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// None for messages without a single letter or digit, those would all share one hash and every one after the first
+// would count as a repost
+pub fn message_hash(message: &str) -> Option<Vec<u8>> {
+    let normalized = normalize(message);
+    if normalized.is_empty() {
+        return None;
+    }
+    Some(Sha256::digest(normalized.as_bytes()).to_vec())
+}
+
+// Hash of a stored file, the same one the post form computes while receiving it
+pub async fn file_hash(file_path: &str) -> Option<Vec<u8>> {
+    tokio::fs::read(file_path).await.ok().map(|data| Sha256::digest(&data).to_vec())
+}
+
+// Whether a message or image hash was seen on the board before
+async fn seen(conn: &mut Conn, board_name: &str, kind: &str, hash: &[u8]) -> Result<bool, mysql_async::Error> {
+    let query = format!("SELECT COUNT(*) FROM {}_r9k WHERE kind = ? AND hash = ?", board_name);
+    info!("Executing query: {}", query);
+    let count: Option<i64> = conn.exec_first(&query, (kind, hash)).await?;
+    Ok(count.unwrap_or(0) > 0)
+}
+
+pub async fn is_repeat(
+    conn: &mut Conn,
+    board_name: &str,
+    message_hash: Option<&[u8]>,
+    image_hash: Option<&[u8]>,
+) -> Result<bool, mysql_async::Error> {
+    if let Some(message_hash) = message_hash {
+        if seen(conn, board_name, KIND_MESSAGE, message_hash).await? {
+            return Ok(true);
+        }
+    }
+    match image_hash {
+        Some(image_hash) => seen(conn, board_name, KIND_IMAGE, image_hash).await,
+        None => Ok(false),
+    }
+}
+
+// Remember the hashes of a published post. Held posts are only remembered once approved, so a rejected one doesn't
+// keep its text from being posted again.
+pub async fn remember(
+    conn: &mut Conn,
+    board_name: &str,
+    message_hash: Option<&[u8]>,
+    image_hash: Option<&[u8]>,
+) -> Result<(), mysql_async::Error> {
+    let query = format!("INSERT IGNORE INTO {}_r9k (kind, hash) VALUES (?, ?)", board_name);
+    info!("Executing query: {}", query);
+    if let Some(message_hash) = message_hash {
+        conn.exec_drop(&query, (KIND_MESSAGE, message_hash)).await?;
+    }
+    if let Some(image_hash) = image_hash {
+        conn.exec_drop(&query, (KIND_IMAGE, image_hash)).await?;
+    }
+    Ok(())
+}
+
+// Seconds the poster is still muted for on the board, 0 when they may post
+pub async fn muted_for(conn: &mut Conn, board_name: &str, stored_ip: &str) -> Result<i64, mysql_async::Error> {
+    let query = "SELECT GREATEST(UNIX_TIMESTAMP(muted_until) - UNIX_TIMESTAMP(CURRENT_TIMESTAMP), 0) FROM r9k_mutes WHERE board = ? AND ip = ?";
+    info!("Executing query: {}", query);
+    let remaining: Option<i64> = conn.exec_first(query, (board_name, stored_ip)).await?;
+    Ok(remaining.unwrap_or(0))
+}
+
+// Count another offense and mute the poster for twice as long as the last time, returning the mute in seconds
+pub async fn punish(conn: &mut Conn, board_name: &str, stored_ip: &str) -> Result<i64, mysql_async::Error> {
+    let query = "SELECT offenses, UNIX_TIMESTAMP(CURRENT_TIMESTAMP) - UNIX_TIMESTAMP(last_offense) FROM r9k_mutes WHERE board = ? AND ip = ?";
+    info!("Executing query: {}", query);
+    let previous: Option<(i64, i64)> = conn.exec_first(query, (board_name, stored_ip)).await?;
+
+    let offenses = match previous {
+        Some((offenses, since_last)) => (offenses - since_last / OFFENSE_DECAY_SECONDS).max(0) + 1,
+        None => 1,
+    };
+    let mute = 2i64.checked_pow(offenses.min(62) as u32).unwrap_or(MAX_MUTE_SECONDS).min(MAX_MUTE_SECONDS);
+
+    let update_query = "INSERT INTO r9k_mutes (board, ip, offenses, last_offense, muted_until) \
+                        VALUES (?, ?, ?, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP + INTERVAL ? SECOND) \
+                        ON DUPLICATE KEY UPDATE offenses = VALUES(offenses), last_offense = VALUES(last_offense), muted_until = VALUES(muted_until)";
+    info!("Executing query: {}", update_query);
+    conn.exec_drop(update_query, (board_name, stored_ip, offenses, mute)).await?;
+    Ok(mute)
+}
+
+pub fn muted(req: &HttpRequest, seconds: i64, unoriginal: bool) -> HttpResponse {
+    let back = back_url(req, "/");
+    let reason = if unoriginal {
+        "This board only accepts original content and that has been posted before."
+    } else {
+        "You posted unoriginal content on this board."
+    };
+    let message = if seconds > 0 {
+        format!("{} You are muted for {} second{}.", reason, seconds, if seconds == 1 { "" } else { "s" })
+    } else {
+        reason.to_string()
+    };
+    HttpResponse::Forbidden()
+        .append_header(("Retry-After", seconds.to_string()))
+        .content_type("text/html")
+        .body(message_page("Muted", &message, &back))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_punctuation_and_spacing_are_ignored() {
+        assert_eq!(normalize("Hello, World!"), "helloworld");
+        assert_eq!(message_hash("Hello, World!"), message_hash("  hello world  "));
+        assert_ne!(message_hash("hello world"), message_hash("hello there"));
+    }
+
+    #[test]
+    fn letters_of_other_scripts_count() {
+        assert_eq!(normalize("Ÿ É ß 日本"), "ÿéß日本");
+    }
+
+    #[test]
+    fn messages_without_letters_or_digits_have_no_hash() {
+        assert_eq!(normalize("!!! ... 🎉🎉"), "");
+        assert_eq!(message_hash("!!! ... 🎉🎉"), None);
+        assert_eq!(message_hash(""), None);
+    }
+}
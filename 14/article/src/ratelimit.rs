@@ -0,0 +1,220 @@
+use crate::boards::BoardSettings;
+use crate::config::Config;
+use crate::{back_url, message_page};
+use actix_web::{HttpRequest, HttpResponse};
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::Conn;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Above this many tracked addresses, buckets that have refilled completely are forgotten
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, capacity: f64, refill_per_second: f64) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * refill_per_second).min(capacity)
+    }
+
+    // Take a token, or return how many seconds to wait until one is available
+    fn take(&mut self, now: Instant, capacity: f64, refill_per_second: f64) -> Result<(), u64> {
+        self.tokens = self.refilled(now, capacity, refill_per_second);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - self.tokens) / refill_per_second).ceil() as u64)
+        }
+    }
+}
+
+// Global per-IP token bucket for posting, across all boards. Kept in memory, a restart refills every bucket.
+pub struct PostLimiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    capacity: f64,
+    refill_per_second: f64,
+}
+
+impl PostLimiter {
+    pub fn new(config: &Config) -> PostLimiter {
+        PostLimiter::with_rate(config.post_burst, config.posts_per_minute)
+    }
+
+    // `burst` tokens at most, refilled at `per_minute` tokens a minute
+    pub fn with_rate(burst: u32, per_minute: u32) -> PostLimiter {
+        PostLimiter {
+            buckets: Mutex::new(HashMap::new()),
+            capacity: burst.max(1) as f64,
+            refill_per_second: per_minute.max(1) as f64 / 60.0,
+        }
+    }
+
+    // Take a token for `ip`, or return how many seconds to wait until one is available
+    pub fn take(&self, ip: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED_ADDRESSES {
+            buckets.retain(|_, bucket| bucket.refilled(now, self.capacity, self.refill_per_second) < self.capacity);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: self.capacity, updated: now });
+        bucket.take(now, self.capacity, self.refill_per_second)
+    }
+}
+
+// Token buckets of the posting API's keys, each refilling at its key's own rate with a minute's worth as the burst
+#[derive(Default)]
+pub struct KeyLimiter {
+    buckets: Mutex<HashMap<i32, Bucket>>,
+}
+
+impl KeyLimiter {
+    pub fn take(&self, key_id: i32, posts_per_minute: u32) -> Result<(), u64> {
+        let now = Instant::now();
+        let capacity = posts_per_minute.max(1) as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key_id).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.take(now, capacity, capacity / 60.0)
+    }
+}
+
+// Seconds left on each of a board's cooldowns for one poster
+pub struct Cooldowns {
+    thread: u64,
+    reply: u64,
+    file: u64,
+}
+
+impl Cooldowns {
+    // How long the poster still has to wait before making this kind of post, 0 when they may post now
+    pub fn wait(&self, is_reply: bool, has_file: bool) -> u64 {
+        let post_wait = if is_reply { self.reply } else { self.thread };
+        let file_wait = if has_file { self.file } else { 0 };
+        post_wait.max(file_wait)
+    }
+}
+
+// Last thread, reply and file post of a poster, plus the database's clock
+type LastPosts = (Option<i64>, Option<i64>, Option<i64>, i64);
+
+fn remaining(last_post: Option<i64>, now: i64, cooldown: u32) -> u64 {
+    last_post
+        .map(|last_post| (last_post + cooldown as i64 - now).max(0) as u64)
+        .unwrap_or(0)
+}
+
+// Look up the poster's last thread, reply and file post on the board, by the address stored with their posts
+pub async fn cooldowns(
+    conn: &mut Conn,
+    board_name: &str,
+    stored_ip: Option<&str>,
+    settings: &BoardSettings,
+) -> Result<Cooldowns, mysql_async::Error> {
+    let none = Cooldowns { thread: 0, reply: 0, file: 0 };
+    let Some(stored_ip) = stored_ip else {
+        return Ok(none);
+    };
+    if settings.thread_cooldown == 0 && settings.reply_cooldown == 0 && settings.file_cooldown == 0 {
+        return Ok(none);
+    }
+
+    let query = format!(
+        "SELECT UNIX_TIMESTAMP(MAX(CASE WHEN parent_id = 0 THEN created_at END)), \
+         UNIX_TIMESTAMP(MAX(CASE WHEN parent_id <> 0 THEN created_at END)), \
+         UNIX_TIMESTAMP(MAX(CASE WHEN file_path IS NOT NULL THEN created_at END)), \
+         UNIX_TIMESTAMP(CURRENT_TIMESTAMP) \
+         FROM {}_files WHERE ip = ?",
+        board_name
+    );
+    info!("Executing query: {}", query);
+    let row: Option<LastPosts> = conn.exec_first(&query, (stored_ip,)).await?;
+    let Some((last_thread, last_reply, last_file, now)) = row else {
+        return Ok(none);
+    };
+
+    Ok(Cooldowns {
+        thread: remaining(last_thread, now, settings.thread_cooldown),
+        reply: remaining(last_reply, now, settings.reply_cooldown),
+        file: remaining(last_file, now, settings.file_cooldown),
+    })
+}
+
+pub fn too_fast(req: &HttpRequest, wait: u64) -> HttpResponse {
+    let back = back_url(req, "/");
+    let message = format!(
+        "You are posting too fast. Please wait {} second{} before posting again.",
+        wait,
+        if wait == 1 { "" } else { "s" }
+    );
+    HttpResponse::TooManyRequests()
+        .append_header(("Retry-After", wait.to_string()))
+        .content_type("text/html")
+        .body(message_page("Slow down", &message, &back))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn limiter_allows_a_burst_then_asks_to_wait() {
+        let limiter = PostLimiter::with_rate(3, 30);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(limiter.take(ip), Ok(()));
+        }
+        assert_eq!(limiter.take(ip), Err(2));
+    }
+
+    #[test]
+    fn addresses_have_separate_buckets() {
+        let limiter = PostLimiter::with_rate(1, 1);
+        assert_eq!(limiter.take("192.0.2.1".parse().unwrap()), Ok(()));
+        assert!(limiter.take("192.0.2.1".parse().unwrap()).is_err());
+        assert_eq!(limiter.take("192.0.2.2".parse().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn bucket_refills_over_time_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = Bucket { tokens: 0.0, updated: start };
+        assert_eq!(bucket.take(start + Duration::from_secs(2), 3.0, 0.5), Ok(()));
+        assert_eq!(bucket.refilled(start + Duration::from_secs(3600), 3.0, 0.5), 3.0);
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let limiter = KeyLimiter::default();
+        assert_eq!(limiter.take(1, 2), Ok(()));
+        assert_eq!(limiter.take(1, 2), Ok(()));
+        assert!(limiter.take(1, 2).is_err());
+        assert_eq!(limiter.take(2, 2), Ok(()));
+    }
+
+    #[test]
+    fn cooldowns_take_the_longer_wait() {
+        let cooldowns = Cooldowns { thread: 50, reply: 5, file: 15 };
+        assert_eq!(cooldowns.wait(false, false), 50);
+        assert_eq!(cooldowns.wait(true, false), 5);
+        assert_eq!(cooldowns.wait(true, true), 15);
+        assert_eq!(cooldowns.wait(false, true), 50);
+    }
+
+    #[test]
+    fn remaining_cooldown() {
+        assert_eq!(remaining(None, 100, 60), 0);
+        assert_eq!(remaining(Some(90), 100, 60), 50);
+        assert_eq!(remaining(Some(10), 100, 60), 0);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::access::BoardAccess;
+use crate::audit::{self, Action};
+use crate::config::Config;
+use crate::moderation::{self, Staff};
+use crate::{back_url, escape_html, identity, message_page, render_template, sanitize_board_name, visitor_timezone};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::{Conn, Pool};
+use std::collections::HashMap;
+
+const CATEGORIES: [(&str, &str); 4] = [
+    ("rules", "Breaks the board rules"),
+    ("spam", "Spam or advertising"),
+    ("illegal", "Illegal content"),
+    ("offtopic", "Off-topic"),
+];
+const MAX_COMMENT_LENGTH: usize = 200;
+const QUEUE_SIZE: usize = 50;
+// Stored for reporters whose address is unknown, so they share one report per post instead of escaping the limit
+const UNKNOWN_IP: &str = "unknown";
+
+fn category_label(category: &str) -> &str {
+    CATEGORIES
+        .iter()
+        .find(|(key, _)| *key == category)
+        .map(|(_, label)| *label)
+        .unwrap_or(category)
+}
+
+// The collapsed report button shown under every post
+pub fn report_form(board_name: &str, id: i32) -> String {
+    let options: String = CATEGORIES
+        .iter()
+        .map(|(key, label)| format!("<option value=\"{}\">{}</option>", key, label))
+        .collect();
+    format!(
+        r#"<details class="report"><summary>Report</summary><form class="report-form" action="/{}/post/{}/report" method="post"><select name="category">{}</select><input type="text" name="comment" maxlength="{}" placeholder="Comment (optional)"><button type="submit">Send Report</button></form></details>"#,
+        board_name, id, options, MAX_COMMENT_LENGTH
+    )
+}
+
+pub async fn submit_report(
+    req: HttpRequest,
+    pool: web::Data<Pool>,
+    config: web::Data<Config>,
+    _access: BoardAccess,
+    path: web::Path<(String, i32)>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let (board_name, id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let back = back_url(&req, "/");
+
+    let category = form.get("category").map(String::as_str).unwrap_or("");
+    if !CATEGORIES.iter().any(|(key, _)| *key == category) {
+        return Ok(HttpResponse::BadRequest().body("Unknown report category."));
+    }
+    let comment = form.get("comment").map(|comment| comment.trim()).unwrap_or("");
+    if comment.chars().count() > MAX_COMMENT_LENGTH {
+        return Ok(HttpResponse::BadRequest().body("Comment is too long."));
+    }
+
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let exists_query = format!("SELECT COUNT(*) FROM {}_files WHERE id = ? AND pending = FALSE", board_name);
+    info!("Executing query: {}", exists_query);
+    let exists: i64 = conn.exec_first(&exists_query, (id,)).await.map_err(ErrorInternalServerError)?.unwrap_or(0);
+    if exists == 0 {
+        return Ok(HttpResponse::NotFound().body("Post not found."));
+    }
+
+    // One report per post and address; repeated reports are silently ignored
+    let stored_ip = identity::client_ip(&req, &config)
+        .map(|ip| identity::stored_ip(ip, &config))
+        .unwrap_or_else(|| UNKNOWN_IP.to_string());
+    let comment = if comment.is_empty() { None } else { Some(comment) };
+    let query = "INSERT IGNORE INTO reports (board, post_id, category, comment, ip) VALUES (?, ?, ?, ?, ?)";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (&board_name, id, category, comment, stored_ip)).await.map_err(ErrorInternalServerError)?;
+
+    let message = if conn.affected_rows() == 0 {
+        "You already reported this post."
+    } else {
+        "Thank you, the report was sent to the moderators."
+    };
+    Ok(HttpResponse::Ok().content_type("text/html").body(message_page("Report", message, &back)))
+}
+
+// Close every report on a post
+pub async fn dismiss(conn: &mut Conn, board_name: &str, id: i32) -> Result<(), mysql_async::Error> {
+    let query = "DELETE FROM reports WHERE board = ? AND post_id = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (board_name, id)).await
+}
+
+// Reported posts, the most reported first
+pub async fn report_queue(
+    req: HttpRequest,
+    staff: Staff,
+    pool: web::Data<Pool>,
+) -> Result<HttpResponse> {
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    let query = format!(
+        "SELECT board, post_id, COUNT(*) AS report_count FROM reports GROUP BY board, post_id \
+         ORDER BY report_count DESC, MAX(created_at) DESC LIMIT {}",
+        QUEUE_SIZE
+    );
+    info!("Executing query: {}", query);
+    let reported: Vec<(String, i32, i64)> = conn.query(&query).await.map_err(ErrorInternalServerError)?;
+
+    let timezone = visitor_timezone(&req);
+    let mut reports_html = String::new();
+    for (board_name, id, report_count) in reported {
+        let Some(post) = moderation::load_post(&mut conn, &board_name, id).await.map_err(ErrorInternalServerError)? else {
+            // The post is already gone, its reports are moot
+            dismiss(&mut conn, &board_name, id).await.map_err(ErrorInternalServerError)?;
+            continue;
+        };
+
+        let details_query = "SELECT category, comment FROM reports WHERE board = ? AND post_id = ? ORDER BY id";
+        info!("Executing query: {}", details_query);
+        let details: Vec<(String, Option<String>)> = conn.exec(details_query, (&board_name, id)).await.map_err(ErrorInternalServerError)?;
+
+        let reasons: String = details
+            .iter()
+            .map(|(category, comment)| match comment {
+                Some(comment) => format!("<li>{}: {}</li>", category_label(category), escape_html(comment)),
+                None => format!("<li>{}</li>", category_label(category)),
+            })
+            .collect();
+
+        reports_html.push_str("<div class=\"report-entry\">");
+        reports_html.push_str(&format!(
+            "<div class=\"report-count\">{} report{}</div><ul>{}</ul>",
+            report_count,
+            if report_count == 1 { "" } else { "s" },
+            reasons
+        ));
+        reports_html.push_str(&moderation::render_mod_post(&post, &staff, timezone));
+        reports_html.push_str(&format!(
+            r#"<form class="delete-form" action="/mod/reports/{}/{}/dismiss" method="post"><button type="submit">Dismiss Reports</button></form>"#,
+            board_name, id
+        ));
+        reports_html.push_str("</div>");
+    }
+    if reports_html.is_empty() {
+        reports_html.push_str("<p>No open reports.</p>");
+    }
+
+    let mut context = HashMap::new();
+    context.insert("MOD_NAV", moderation::nav(&staff));
+    context.insert("REPORTS", reports_html);
+    let body = render_template("templates/mod_reports.html", &context);
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub async fn dismiss_reports(
+    staff: Staff,
+    pool: web::Data<Pool>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse> {
+    let (board_name, id) = path.into_inner();
+    let board_name = sanitize_board_name(&board_name);
+    let mut conn = pool.get_conn().await.map_err(ErrorInternalServerError)?;
+    dismiss(&mut conn, &board_name, id).await.map_err(ErrorInternalServerError)?;
+    audit::record(&mut conn, &staff, Action::DismissReports, Some(&board_name), Some(id), None, None)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther().append_header(("Location", "/mod/reports")).finish())
+}
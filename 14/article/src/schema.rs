@@ -0,0 +1,312 @@
+use log::info;
+use mysql_async::prelude::*;
+use mysql_async::Conn;
+
+// Tables shared by every board, created when the server starts
+const GLOBAL_TABLES: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS boards (
+        name VARCHAR(64) PRIMARY KEY,
+        title VARCHAR(100) NOT NULL DEFAULT '',
+        description VARCHAR(255) NOT NULL DEFAULT '',
+        rules TEXT,
+        nsfw BOOLEAN NOT NULL DEFAULT FALSE,
+        max_title_length SMALLINT UNSIGNED NOT NULL DEFAULT 30,
+        max_message_length INT UNSIGNED NOT NULL DEFAULT 50000,
+        preview_length INT UNSIGNED NOT NULL DEFAULT 2700,
+        threads_per_page SMALLINT UNSIGNED NOT NULL DEFAULT 30,
+        allowed_file_types VARCHAR(255) NOT NULL DEFAULT 'jpg,jpeg,png,gif,webp,mp4,mp3,webm',
+        op_file_required BOOLEAN NOT NULL DEFAULT FALSE,
+        text_only BOOLEAN NOT NULL DEFAULT FALSE,
+        default_name VARCHAR(50) NOT NULL DEFAULT 'Anonymous',
+        category VARCHAR(64) NOT NULL DEFAULT '',
+        visibility VARCHAR(8) NOT NULL DEFAULT 'public',
+        access_password VARCHAR(255),
+        forced_anon BOOLEAN NOT NULL DEFAULT FALSE,
+        poster_ids BOOLEAN NOT NULL DEFAULT FALSE,
+        public_log BOOLEAN NOT NULL DEFAULT FALSE,
+        thread_cooldown INT UNSIGNED NOT NULL DEFAULT 60,
+        reply_cooldown INT UNSIGNED NOT NULL DEFAULT 10,
+        file_cooldown INT UNSIGNED NOT NULL DEFAULT 20,
+        captcha VARCHAR(8) NOT NULL DEFAULT 'off',
+        pow_difficulty TINYINT UNSIGNED NOT NULL DEFAULT 0,
+        r9k BOOLEAN NOT NULL DEFAULT FALSE,
+        r9k_images BOOLEAN NOT NULL DEFAULT FALSE,
+        approval VARCHAR(8) NOT NULL DEFAULT 'off',
+        owner_id INT,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        INDEX (owner_id)
+    )",
+    "CREATE TABLE IF NOT EXISTS staff (
+        id INT AUTO_INCREMENT PRIMARY KEY,
+        username VARCHAR(32) NOT NULL UNIQUE,
+        password_hash VARCHAR(255) NOT NULL,
+        role VARCHAR(16) NOT NULL DEFAULT 'admin',
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    )",
+    "CREATE TABLE IF NOT EXISTS staff_sessions (
+        token CHAR(64) PRIMARY KEY,
+        staff_id INT NOT NULL,
+        expires_at TIMESTAMP NOT NULL,
+        FOREIGN KEY (staff_id) REFERENCES staff(id) ON DELETE CASCADE
+    )",
+    "CREATE TABLE IF NOT EXISTS staff_boards (
+        staff_id INT NOT NULL,
+        board VARCHAR(64) NOT NULL,
+        PRIMARY KEY (staff_id, board),
+        FOREIGN KEY (staff_id) REFERENCES staff(id) ON DELETE CASCADE
+    )",
+    "CREATE TABLE IF NOT EXISTS board_invites (
+        token CHAR(32) PRIMARY KEY,
+        board VARCHAR(64) NOT NULL,
+        uses_left INT,
+        expires_at TIMESTAMP NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        INDEX (board)
+    )",
+    "CREATE TABLE IF NOT EXISTS board_sessions (
+        token CHAR(64) PRIMARY KEY,
+        board VARCHAR(64) NOT NULL,
+        expires_at TIMESTAMP NOT NULL,
+        INDEX (board)
+    )",
+    "CREATE TABLE IF NOT EXISTS api_keys (
+        id INT AUTO_INCREMENT PRIMARY KEY,
+        label VARCHAR(64) NOT NULL,
+        token_hash CHAR(64) NOT NULL UNIQUE,
+        posts_per_minute INT UNSIGNED NOT NULL DEFAULT 10,
+        scoped BOOLEAN NOT NULL DEFAULT FALSE,
+        revoked BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        last_used_at TIMESTAMP NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS api_key_boards (
+        key_id INT NOT NULL,
+        board VARCHAR(64) NOT NULL,
+        PRIMARY KEY (key_id, board),
+        FOREIGN KEY (key_id) REFERENCES api_keys(id) ON DELETE CASCADE
+    )",
+    "CREATE TABLE IF NOT EXISTS bans (
+        id INT AUTO_INCREMENT PRIMARY KEY,
+        ip_range VARCHAR(64),
+        ip_hash VARCHAR(64),
+        board VARCHAR(64),
+        reason TEXT NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        expires_at TIMESTAMP NULL,
+        staff_id INT
+    )",
+    "CREATE TABLE IF NOT EXISTS reports (
+        id INT AUTO_INCREMENT PRIMARY KEY,
+        board VARCHAR(64) NOT NULL,
+        post_id INT NOT NULL,
+        category VARCHAR(16) NOT NULL,
+        comment VARCHAR(200),
+        ip VARCHAR(64) NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        UNIQUE KEY one_report_per_ip (board, post_id, ip)
+    )",
+    "CREATE TABLE IF NOT EXISTS mod_log (
+        id INT AUTO_INCREMENT PRIMARY KEY,
+        staff_id INT NOT NULL,
+        staff_name VARCHAR(32) NOT NULL,
+        action VARCHAR(32) NOT NULL,
+        board VARCHAR(64),
+        post_id INT,
+        reason TEXT,
+        details TEXT,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        INDEX (board),
+        INDEX (staff_name)
+    )",
+    "CREATE TABLE IF NOT EXISTS filters (
+        id INT AUTO_INCREMENT PRIMARY KEY,
+        board VARCHAR(64),
+        pattern TEXT NOT NULL,
+        is_regex BOOLEAN NOT NULL DEFAULT FALSE,
+        action VARCHAR(16) NOT NULL,
+        replacement TEXT,
+        message TEXT,
+        ban_hours INT NOT NULL DEFAULT 0,
+        staff_id INT,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    )",
+    "CREATE TABLE IF NOT EXISTS r9k_mutes (
+        board VARCHAR(64) NOT NULL,
+        ip VARCHAR(64) NOT NULL,
+        offenses INT NOT NULL DEFAULT 0,
+        last_offense TIMESTAMP NULL,
+        muted_until TIMESTAMP NULL,
+        PRIMARY KEY (board, ip)
+    )",
+    "CREATE TRIGGER IF NOT EXISTS mod_log_no_update BEFORE UPDATE ON mod_log FOR EACH ROW SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'mod_log is append-only'",
+    "CREATE TRIGGER IF NOT EXISTS mod_log_no_delete BEFORE DELETE ON mod_log FOR EACH ROW SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'mod_log is append-only'",
+];
+
+// Columns added to the shared tables since they were introduced
+const GLOBAL_MIGRATIONS: &[&str] = &[
+    "ALTER TABLE boards ADD COLUMN poster_ids BOOLEAN NOT NULL DEFAULT FALSE",
+    "ALTER TABLE boards ADD COLUMN public_log BOOLEAN NOT NULL DEFAULT FALSE",
+    "ALTER TABLE boards ADD COLUMN thread_cooldown INT UNSIGNED NOT NULL DEFAULT 60",
+    "ALTER TABLE boards ADD COLUMN reply_cooldown INT UNSIGNED NOT NULL DEFAULT 10",
+    "ALTER TABLE boards ADD COLUMN file_cooldown INT UNSIGNED NOT NULL DEFAULT 20",
+    "ALTER TABLE boards ADD COLUMN captcha VARCHAR(8) NOT NULL DEFAULT 'off'",
+    "ALTER TABLE boards ADD COLUMN pow_difficulty TINYINT UNSIGNED NOT NULL DEFAULT 0",
+    "ALTER TABLE boards ADD COLUMN r9k BOOLEAN NOT NULL DEFAULT FALSE",
+    "ALTER TABLE boards ADD COLUMN r9k_images BOOLEAN NOT NULL DEFAULT FALSE",
+    "ALTER TABLE boards ADD COLUMN approval VARCHAR(8) NOT NULL DEFAULT 'off'",
+    "ALTER TABLE staff ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'admin' AFTER password_hash",
+    "ALTER TABLE boards ADD COLUMN title VARCHAR(100) NOT NULL DEFAULT '' AFTER name",
+    "ALTER TABLE boards ADD COLUMN description VARCHAR(255) NOT NULL DEFAULT '' AFTER title",
+    "ALTER TABLE boards ADD COLUMN rules TEXT AFTER description",
+    "ALTER TABLE boards ADD COLUMN nsfw BOOLEAN NOT NULL DEFAULT FALSE AFTER rules",
+    "ALTER TABLE boards ADD COLUMN max_title_length SMALLINT UNSIGNED NOT NULL DEFAULT 30 AFTER nsfw",
+    "ALTER TABLE boards ADD COLUMN max_message_length INT UNSIGNED NOT NULL DEFAULT 50000 AFTER max_title_length",
+    "ALTER TABLE boards ADD COLUMN preview_length INT UNSIGNED NOT NULL DEFAULT 2700 AFTER max_message_length",
+    "ALTER TABLE boards ADD COLUMN threads_per_page SMALLINT UNSIGNED NOT NULL DEFAULT 30 AFTER preview_length",
+    "ALTER TABLE boards ADD COLUMN allowed_file_types VARCHAR(255) NOT NULL DEFAULT 'jpg,jpeg,png,gif,webp,mp4,mp3,webm' AFTER threads_per_page",
+    "ALTER TABLE boards ADD COLUMN op_file_required BOOLEAN NOT NULL DEFAULT FALSE AFTER allowed_file_types",
+    "ALTER TABLE boards ADD COLUMN text_only BOOLEAN NOT NULL DEFAULT FALSE AFTER op_file_required",
+    "ALTER TABLE boards ADD COLUMN default_name VARCHAR(50) NOT NULL DEFAULT 'Anonymous' AFTER text_only",
+    "ALTER TABLE boards ADD COLUMN category VARCHAR(64) NOT NULL DEFAULT '' AFTER default_name",
+    "ALTER TABLE boards ADD COLUMN visibility VARCHAR(8) NOT NULL DEFAULT 'public' AFTER category",
+    "ALTER TABLE boards ADD COLUMN access_password VARCHAR(255) AFTER visibility",
+    "ALTER TABLE boards ADD COLUMN owner_id INT",
+    "ALTER TABLE boards ADD COLUMN created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP",
+    "ALTER TABLE boards ADD INDEX owner_id (owner_id)",
+];
+
+// Tables of each board; `{board}` is replaced with the board name
+const BOARD_TABLES: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS {board}_files (
+        id INT AUTO_INCREMENT PRIMARY KEY,
+        post_id VARCHAR(255) NOT NULL,
+        parent_id INT,
+        poster_id VARCHAR(8),
+        name VARCHAR(50) NOT NULL DEFAULT 'Anonymous',
+        tripcode VARCHAR(20),
+        title VARCHAR(100) NOT NULL,
+        message TEXT NOT NULL,
+        file_path VARCHAR(255),
+        delete_hash VARCHAR(255),
+        ip VARCHAR(64),
+        pending BOOLEAN NOT NULL DEFAULT FALSE,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        last_reply_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+        INDEX (ip)
+    )",
+    "CREATE TABLE IF NOT EXISTS {board}_r9k (
+        kind CHAR(1) NOT NULL,
+        hash BINARY(32) NOT NULL,
+        PRIMARY KEY (kind, hash)
+    )",
+];
+
+// Brings the tables of a board created by an older version up to date; `{board}` is replaced with the board name
+const BOARD_MIGRATIONS: &[&str] = &[
+    "ALTER TABLE {board}_files ADD COLUMN created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP AFTER file_path",
+    "UPDATE {board}_files SET created_at = last_reply_at, last_reply_at = last_reply_at WHERE created_at > last_reply_at",
+    "ALTER TABLE {board}_files ADD COLUMN name VARCHAR(50) NOT NULL DEFAULT 'Anonymous' AFTER parent_id",
+    "ALTER TABLE {board}_files ADD COLUMN tripcode VARCHAR(20) AFTER name",
+    "ALTER TABLE {board}_files ADD COLUMN poster_id VARCHAR(8) AFTER parent_id",
+    "ALTER TABLE {board}_files ADD COLUMN delete_hash VARCHAR(255) AFTER file_path",
+    "ALTER TABLE {board}_files ADD COLUMN ip VARCHAR(64) AFTER delete_hash",
+    "ALTER TABLE {board}_files ADD INDEX ip (ip)",
+    "ALTER TABLE {board}_files ADD COLUMN pending BOOLEAN NOT NULL DEFAULT FALSE AFTER ip",
+    // Room for boards that allow titles longer than the original 30 characters
+    "ALTER TABLE {board}_files MODIFY title VARCHAR(100) NOT NULL",
+];
+
+// Shared tables that refer to boards by name, updated on rename and cleaned up on delete
+const BOARD_REFERENCES: &[&str] = &["bans", "reports", "filters", "r9k_mutes", "staff_boards", "board_invites", "board_sessions", "api_key_boards"];
+
+// MySQL error codes for a column or index that already exists
+const ER_DUP_FIELDNAME: u16 = 1060;
+const ER_DUP_KEYNAME: u16 = 1061;
+
+async fn execute(conn: &mut Conn, statement: &str) -> Result<(), mysql_async::Error> {
+    info!("Executing query: {}", statement);
+    conn.query_drop(statement).await
+}
+
+// Run a migration, treating one that was already applied as done
+async fn apply(conn: &mut Conn, statement: &str) -> Result<(), mysql_async::Error> {
+    match execute(conn, statement).await {
+        Err(mysql_async::Error::Server(err)) if err.code == ER_DUP_FIELDNAME || err.code == ER_DUP_KEYNAME => Ok(()),
+        result => result,
+    }
+}
+
+pub async fn init(conn: &mut Conn) -> Result<(), mysql_async::Error> {
+    for statement in GLOBAL_TABLES {
+        execute(conn, statement).await?;
+    }
+    Ok(())
+}
+
+// Update the shared tables and every board, registering boards the `boards` table doesn't know yet.
+// Returns the names of the migrated boards.
+pub async fn migrate(conn: &mut Conn) -> Result<Vec<String>, mysql_async::Error> {
+    init(conn).await?;
+    for statement in GLOBAL_MIGRATIONS {
+        apply(conn, statement).await?;
+    }
+
+    let query = "SELECT LEFT(table_name, CHAR_LENGTH(table_name) - 6) FROM information_schema.tables \
+                 WHERE table_schema = DATABASE() AND table_name LIKE '%\\_files'";
+    info!("Executing query: {}", query);
+    let boards: Vec<String> = conn.query(query).await?;
+    for board in &boards {
+        let register_query = "INSERT IGNORE INTO boards (name) VALUES (?)";
+        info!("Executing query: {}", register_query);
+        conn.exec_drop(register_query, (board,)).await?;
+        for statement in BOARD_MIGRATIONS.iter().chain(BOARD_TABLES) {
+            apply(conn, &statement.replace("{board}", board)).await?;
+        }
+    }
+    Ok(boards)
+}
+
+// `board_name` must already be sanitized
+pub async fn create_board(conn: &mut Conn, board_name: &str) -> Result<(), mysql_async::Error> {
+    for statement in BOARD_TABLES {
+        execute(conn, &statement.replace("{board}", board_name)).await?;
+    }
+    let query = "INSERT IGNORE INTO boards (name) VALUES (?)";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (board_name,)).await
+}
+
+// Drop a board's tables and everything referring to it, except the append-only moderation log
+pub async fn drop_board(conn: &mut Conn, board_name: &str) -> Result<(), mysql_async::Error> {
+    execute(conn, &format!("DROP TABLE IF EXISTS {}_files, {}_r9k", board_name, board_name)).await?;
+    for table in BOARD_REFERENCES {
+        let query = format!("DELETE FROM {} WHERE board = ?", table);
+        info!("Executing query: {}", query);
+        conn.exec_drop(&query, (board_name,)).await?;
+    }
+    let query = "DELETE FROM boards WHERE name = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (board_name,)).await
+}
+
+// Both names must already be sanitized. Moderation log entries keep the old name.
+pub async fn rename_board(conn: &mut Conn, old_name: &str, new_name: &str) -> Result<(), mysql_async::Error> {
+    execute(
+        conn,
+        &format!(
+            "RENAME TABLE {old}_files TO {new}_files, {old}_r9k TO {new}_r9k",
+            old = old_name,
+            new = new_name
+        ),
+    )
+    .await?;
+    let query = "UPDATE boards SET name = ? WHERE name = ?";
+    info!("Executing query: {}", query);
+    conn.exec_drop(query, (new_name, old_name)).await?;
+    for table in BOARD_REFERENCES {
+        let query = format!("UPDATE {} SET board = ? WHERE board = ?", table);
+        info!("Executing query: {}", query);
+        conn.exec_drop(&query, (new_name, old_name)).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use sha2::{Digest, Sha256};
+
+pub const DEFAULT_NAME: &str = "Anonymous";
+
+// Split the raw name field into the display name and an optional tripcode.
+// `Name#password` yields a classic tripcode, `Name##password` a secure one salted with the server secret.
+// The password itself is never returned, only the derived tripcode. Posts without a name get `default_name`.
+pub fn parse_name(raw: &str, secret: &str, default_name: &str) -> (String, Option<String>) {
+    let (name, password) = match raw.split_once('#') {
+        Some((name, password)) => (name.trim(), Some(password)),
+        None => (raw.trim(), None),
+    };
+
+    let name = if name.is_empty() { default_name.to_string() } else { name.to_string() };
+
+    let tripcode = match password {
+        Some(password) => match password.strip_prefix('#') {
+            Some(secure) if !secure.is_empty() => Some(secure_tripcode(secure, secret)),
+            Some(_) => None,
+            None if !password.is_empty() => classic_tripcode(password),
+            None => None,
+        },
+        None => None,
+    };
+
+    (name, tripcode)
+}
+
+// The traditional DES crypt(3) tripcode, compatible with other imageboards
+#[allow(deprecated)]
+fn classic_tripcode(password: &str) -> Option<String> {
+    let password: String = password.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '"' => "&quot;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            c => c.to_string(),
+        })
+        .collect();
+
+    let salt: String = format!("{}H.", password)
+        .chars()
+        .skip(1)
+        .take(2)
+        .map(|c| match c {
+            ':' => 'A',
+            ';' => 'B',
+            '<' => 'C',
+            '=' => 'D',
+            '>' => 'E',
+            '?' => 'F',
+            '@' => 'G',
+            '[' => 'a',
+            '\\' => 'b',
+            ']' => 'c',
+            '^' => 'd',
+            '_' => 'e',
+            '`' => 'f',
+            c if ('.'..='z').contains(&c) => c,
+            _ => '.',
+        })
+        .collect();
+
+    let hash = pwhash::unix_crypt::hash_with(&salt, password.as_bytes()).ok()?;
+    Some(format!("!{}", &hash[hash.len() - 10..]))
+}
+
+fn secure_tripcode(password: &str, secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(secret.as_bytes());
+    hasher.update(password.as_bytes());
+    let encoded = STANDARD.encode(hasher.finalize());
+    format!("!!{}", &encoded[..10])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_without_password_has_no_tripcode() {
+        assert_eq!(parse_name("  Alice ", "secret", DEFAULT_NAME), ("Alice".to_string(), None));
+    }
+
+    #[test]
+    fn empty_name_falls_back_to_default() {
+        assert_eq!(parse_name("", "secret", "Nameless"), ("Nameless".to_string(), None));
+        assert_eq!(parse_name("#", "secret", "Nameless"), ("Nameless".to_string(), None));
+        assert_eq!(parse_name("##", "secret", "Nameless"), ("Nameless".to_string(), None));
+    }
+
+    #[test]
+    fn classic_tripcode_matches_other_imageboards() {
+        assert_eq!(parse_name("Bob#test", "secret", DEFAULT_NAME), ("Bob".to_string(), Some("!.CzKQna1OU".to_string())));
+    }
+
+    #[test]
+    fn secure_tripcode_depends_on_the_secret() {
+        let (name, tripcode) = parse_name("Bob##test", "one", DEFAULT_NAME);
+        let tripcode = tripcode.unwrap();
+        assert_eq!(name, "Bob");
+        assert!(tripcode.starts_with("!!"));
+        assert_eq!(tripcode.len(), 12);
+        assert_eq!(parse_name("##test", "one", DEFAULT_NAME).1.as_ref(), Some(&tripcode));
+        assert_ne!(parse_name("Bob##test", "two", DEFAULT_NAME).1, Some(tripcode));
+    }
+
+    #[test]
+    fn password_is_never_part_of_the_name() {
+        let (name, tripcode) = parse_name("Eve#hunter2", "secret", DEFAULT_NAME);
+        assert_eq!(name, "Eve");
+        assert!(!tripcode.unwrap().contains("hunter2"));
+    }
+}